@@ -0,0 +1,441 @@
+//
+// adc.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:26:47 +0000 (UTC)
+//
+
+use gpio;
+use rcc;
+use dma;
+use systick;
+
+#[repr(packed)]
+struct ADC_register_map {
+  SR: u32,
+  CR1: u32,
+  CR2: u32,
+  SMPR1: u32,
+  SMPR2: u32,
+  JOFR1: u32,
+  JOFR2: u32,
+  JOFR3: u32,
+  JOFR4: u32,
+  HTR: u32,
+  LTR: u32,
+  SQR1: u32,
+  SQR2: u32,
+  SQR3: u32,
+  JSQR: u32,
+  JDR1: u32,
+  JDR2: u32,
+  JDR3: u32,
+  JDR4: u32,
+  DR: u32,
+}
+
+/// End of conversion (of the whole sequence in the scan mode)
+const ADC_SR_EOC: u32 = 1 << 1;
+
+/// Scan mode (convert all the channels in the sequence, not just the first)
+const ADC_CR1_SCAN: u32 = 1 << 8;
+
+/// Temperature sensor and Vrefint enable
+const ADC_CR2_TSVREFE: u32 = 1 << 23;
+/// Start a conversion of the regular channels
+const ADC_CR2_SWSTART: u32 = 1 << 22;
+/// Let the external event selected by EXTSEL start the conversions
+const ADC_CR2_EXTTRIG: u32 = 1 << 20;
+/// External event for the regular channels (0b111 is SWSTART)
+const ADC_CR2_EXTSEL: u32 = 0b111 << 17;
+/// DMA mode
+const ADC_CR2_DMA: u32 = 1 << 8;
+/// Reset calibration (cleared by the hardware once done)
+const ADC_CR2_RSTCAL: u32 = 1 << 3;
+/// Calibration (cleared by the hardware once done)
+const ADC_CR2_CAL: u32 = 1 << 2;
+/// Continuous conversion
+const ADC_CR2_CONT: u32 = 1 << 1;
+/// A/D converter on
+const ADC_CR2_ADON: u32 = 1 << 0;
+
+/// Offset of DR in the register map (for the DMA)
+const ADC_DR_OFFSET: u32 = 0x4c;
+
+/// The internal temperature sensor (ADC1 only)
+pub const TEMPERATURE: u8 = 16;
+/// The internal reference voltage (ADC1 only)
+pub const VREFINT: u8 = 17;
+
+/// Vrefint (typical), in mV
+const VREFINT_MV: u32 = 1200;
+/// The temperature sensor's voltage at 25°C (typical), in mV
+const V25_MV: i32 = 1430;
+/// The temperature sensor's slope (typical), in 10uV/°C
+const AVG_SLOPE: i32 = 430;
+
+/// Full scale of the 12-bit results
+const FULL_SCALE: u32 = 4095;
+
+/// Sampling time in ADCCLK cycles, the longer the higher the source impedance
+/// can be (the temperature sensor and Vrefint need at least 17.1us)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleTime {
+  Cycles1_5 = 0b000,
+  Cycles7_5 = 0b001,
+  Cycles13_5 = 0b010,
+  Cycles28_5 = 0b011,
+  Cycles41_5 = 0b100,
+  Cycles55_5 = 0b101,
+  Cycles71_5 = 0b110,
+  Cycles239_5 = 0b111,
+}
+
+/// Errors of the conversions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+  /// The ADC is busy with the continuous conversions (see start_continuous)
+  Busy,
+  /// The DMA failed to move the results
+  Dma,
+}
+
+/// Base address of the registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ADC {
+  regmap: *mut ADC_register_map,
+}
+
+pub const ADC1: ADC = ADC { regmap: 0x4001_2400 as *mut ADC_register_map };
+pub const ADC2: ADC = ADC { regmap: 0x4001_2800 as *mut ADC_register_map };
+
+/// Only ADC1 is served by the DMA
+const DMA_CHANNEL: dma::Channel = dma::CHANNEL1;
+
+/// The pin of the external channel (0-15)
+pub fn channel_pin(channel: u8) -> Option<(gpio::Gpio, u8)> {
+  match channel {
+    0...7 => Some((gpio::GPIOA, channel)),
+    8...9 => Some((gpio::GPIOB, channel - 8)),
+    10...15 => Some((gpio::GPIOC, channel - 10)),
+    _ => None,
+  }
+}
+
+impl ADC {
+  /// Enable the clock, power the ADC up and calibrate it
+  ///
+  /// The conversions are started by software, the sample times are all at
+  /// the shortest until set_sample_time is used.
+  pub fn initialize(self) {
+    if self == ADC1 {
+      rcc::enable(rcc::Periph::apb2_adc1);
+    } else {
+      rcc::enable(rcc::Periph::apb2_adc2);
+    }
+
+    unsafe {
+      (*self.regmap).CR1 = 0;
+      (*self.regmap).CR2 = ADC_CR2_ADON;
+    }
+
+    // It has to be on for at least two ADCCLK cycles (and tSTAB, 1us) before
+    // the calibration (delay_ms(1) could be over at the very next tick)
+    systick::delay_ms(2);
+
+    unsafe {
+      (*self.regmap).CR2 |= ADC_CR2_RSTCAL;
+      while (*self.regmap).CR2 & ADC_CR2_RSTCAL != 0 {}
+
+      (*self.regmap).CR2 |= ADC_CR2_CAL;
+      while (*self.regmap).CR2 & ADC_CR2_CAL != 0 {}
+
+      // Setting ADON again would start a conversion, SWSTART is used instead
+      (*self.regmap).CR2 |= ADC_CR2_EXTSEL | ADC_CR2_EXTTRIG;
+    }
+
+    debug!("ADC at {:p} calibrated, ADCCLK = {} Hz", self.regmap, rcc::get_clock_speed(rcc::Clock::ADCCLK));
+  }
+
+  pub fn is_initialized(&self) -> bool {
+    unsafe { (*self.regmap).CR2 & ADC_CR2_ADON != 0 }
+  }
+
+  /// Whether start_continuous is in charge of the ADC
+  fn is_continuous(&self) -> bool {
+    unsafe { (*self.regmap).CR2 & ADC_CR2_CONT != 0 }
+  }
+
+  /// Make the pin of the external channel an analog input
+  pub fn configure_pin(&self, channel: u8) {
+    if let Some((port, pin)) = channel_pin(channel) {
+      port.set_pin_mode(pin, gpio::PinMode::Analog);
+    }
+  }
+
+  pub fn set_sample_time(&self, channel: u8, time: SampleTime) {
+    let time = time as u32;
+
+    unsafe {
+      if channel < 10 {
+        let shift = 3 * channel as u32;
+        (*self.regmap).SMPR2 = ((*self.regmap).SMPR2 & !(0b111 << shift)) | time << shift;
+      } else {
+        let shift = 3 * (channel as u32 - 10);
+        (*self.regmap).SMPR1 = ((*self.regmap).SMPR1 & !(0b111 << shift)) | time << shift;
+      }
+    }
+  }
+
+  /// Program the regular sequence (up to 16 channels)
+  fn set_sequence(&self, channels: &[u8]) {
+    let mut sqr = [0u32; 3];
+
+    // SQR3 holds the 1st-6th conversion, SQR2 the 7th-12th, SQR1 the rest
+    for (i, &channel) in channels.iter().take(16).enumerate() {
+      sqr[i / 6] |= (channel as u32 & 0b1_1111) << (5 * (i % 6));
+    }
+
+    let len = if channels.len() > 16 { 16 } else { channels.len() };
+
+    unsafe {
+      (*self.regmap).SQR3 = sqr[0];
+      (*self.regmap).SQR2 = sqr[1];
+      (*self.regmap).SQR1 = sqr[2] | ((len as u32 - 1) & 0b1111) << 20;
+
+      if len > 1 {
+        (*self.regmap).CR1 |= ADC_CR1_SCAN;
+      } else {
+        (*self.regmap).CR1 &= !ADC_CR1_SCAN;
+      }
+    }
+  }
+
+  /// Convert a single channel, returns the 12-bit result
+  ///
+  /// Fails with Error::Busy while the continuous conversions are running.
+  pub fn read(&self, channel: u8) -> Result<u16, Error> {
+    if self.is_continuous() {
+      return Err(Error::Busy);
+    }
+
+    self.set_sequence(&[channel]);
+
+    unsafe {
+      (*self.regmap).CR2 &= !ADC_CR2_DMA;
+      (*self.regmap).CR2 |= ADC_CR2_SWSTART;
+
+      while (*self.regmap).SR & ADC_SR_EOC == 0 {}
+
+      // Reading DR clears EOC
+      Ok((*self.regmap).DR as u16)
+    }
+  }
+
+  /// Convert the channels one after another, the results go to the same
+  /// positions in `results`
+  ///
+  /// Only the last result of a scan stays in DR, so this needs the DMA for a
+  /// real scan, ADC2 (or ADC1 when the DMA channel is taken) just does the
+  /// conversions one by one. Fails like read does, or with Error::Dma if the
+  /// results didn't make it (whatever got to `results` is of no use then).
+  pub fn scan(&self, channels: &[u8], results: &mut [u16]) -> Result<(), Error> {
+    let len = if channels.len() < results.len() { channels.len() } else { results.len() };
+
+    if self.is_continuous() {
+      return Err(Error::Busy);
+    }
+
+    if len == 0 {
+      return Ok(());
+    }
+
+    if len == 1 || len > 16 || *self != ADC1 || !DMA_CHANNEL.claim() {
+      for i in 0..len {
+        results[i] = self.read(channels[i])?;
+      }
+      return Ok(());
+    }
+
+    self.set_sequence(&channels[..len]);
+
+    DMA_CHANNEL.start(&dma::Transfer {
+      peripheral: self.regmap as u32 + ADC_DR_OFFSET,
+      memory: results.as_mut_ptr() as u32,
+      count: len as u16,
+      direction: dma::Direction::PeripheralToMemory,
+      memory_increment: true,
+      size: dma::Size::Bits16,
+      circular: false,
+      priority: dma::Priority::High,
+    });
+
+    unsafe {
+      (*self.regmap).CR2 |= ADC_CR2_DMA;
+      (*self.regmap).CR2 |= ADC_CR2_SWSTART;
+    }
+
+    // Complete on an error too
+    while !DMA_CHANNEL.is_complete() {}
+
+    let failed = DMA_CHANNEL.has_failed();
+
+    unsafe {
+      (*self.regmap).CR2 &= !ADC_CR2_DMA;
+    }
+
+    DMA_CHANNEL.release();
+
+    if failed {
+      return Err(Error::Dma);
+    }
+
+    Ok(())
+  }
+
+  /// Keep converting the channels over and over, with the DMA putting the
+  /// latest results in `buf` (in the order of the channels, repeated if the
+  /// buffer's longer), until stop_continuous is called
+  ///
+  /// Returns false if the DMA channel is taken (or this is ADC2, which has
+  /// no DMA).
+  pub fn start_continuous(&self, channels: &[u8], buf: &'static mut [u16]) -> bool {
+    if channels.is_empty() || channels.len() > 16 || buf.is_empty() || buf.len() > 0xffff {
+      return false;
+    }
+
+    if *self != ADC1 || !DMA_CHANNEL.claim() {
+      return false;
+    }
+
+    self.set_sequence(channels);
+
+    DMA_CHANNEL.start(&dma::Transfer {
+      peripheral: self.regmap as u32 + ADC_DR_OFFSET,
+      memory: buf.as_mut_ptr() as u32,
+      count: buf.len() as u16,
+      direction: dma::Direction::PeripheralToMemory,
+      memory_increment: true,
+      size: dma::Size::Bits16,
+      circular: true,
+      priority: dma::Priority::Medium,
+    });
+
+    unsafe {
+      (*self.regmap).CR2 |= ADC_CR2_CONT | ADC_CR2_DMA;
+      (*self.regmap).CR2 |= ADC_CR2_SWSTART;
+    }
+
+    true
+  }
+
+  pub fn stop_continuous(&self) {
+    unsafe {
+      if (*self.regmap).CR2 & ADC_CR2_CONT == 0 {
+        return;
+      }
+
+      // The conversion in progress still finishes
+      (*self.regmap).CR2 &= !(ADC_CR2_CONT | ADC_CR2_DMA);
+    }
+
+    DMA_CHANNEL.release();
+  }
+}
+
+/// Turn the temperature sensor and Vrefint on (they're connected to ADC1),
+/// initializing ADC1 first if needed
+fn enable_internal_channels() {
+  if !ADC1.is_initialized() {
+    ADC1.initialize();
+  }
+
+  unsafe {
+    if (*ADC1.regmap).CR2 & ADC_CR2_TSVREFE != 0 {
+      return;
+    }
+
+    (*ADC1.regmap).CR2 |= ADC_CR2_TSVREFE;
+  }
+
+  ADC1.set_sample_time(TEMPERATURE, SampleTime::Cycles239_5);
+  ADC1.set_sample_time(VREFINT, SampleTime::Cycles239_5);
+
+  // They need 10us to start up
+  systick::delay_ms(2);
+}
+
+/// The supply (VDDA) voltage in mV, measured against Vrefint with ADC1
+/// (which gets initialized if it wasn't)
+pub fn read_vdda() -> Result<u32, Error> {
+  enable_internal_channels();
+
+  Ok(vdda_from_vrefint(ADC1.read(VREFINT)?))
+}
+
+/// VDDA in mV, given what Vrefint converted to
+fn vdda_from_vrefint(vrefint: u16) -> u32 {
+  if vrefint == 0 {
+    return 0;
+  }
+
+  VREFINT_MV * FULL_SCALE / vrefint as u32
+}
+
+/// Convert a result to mV, given the supply voltage (see read_vdda)
+pub fn to_millivolts(raw: u16, vdda: u32) -> u32 {
+  raw as u32 * vdda / FULL_SCALE
+}
+
+/// The chip's temperature in tenths of a degree Celsius, from the internal
+/// sensor through ADC1 (which gets initialized if it wasn't)
+///
+/// Mind that the sensor's offset varies a lot between the chips (up to 45°C),
+/// so it's only good for watching the changes of the temperature.
+pub fn read_temperature() -> Result<i32, Error> {
+  let vdda = read_vdda()?;
+
+  Ok(temperature_from_vsense(to_millivolts(ADC1.read(TEMPERATURE)?, vdda) as i32))
+}
+
+/// Tenths of a degree Celsius, given the sensor's voltage in mV
+fn temperature_from_vsense(vsense: i32) -> i32 {
+  // (V25 - Vsense) / Avg_Slope + 25, with the slope in 10uV/°C
+  (V25_MV - vsense) * 1000 / AVG_SLOPE + 250
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn vdda() {
+    // Vrefint at 1.2V reads 1489 with a 3.3V supply
+    assert_eq!(vdda_from_vrefint(1489), 3300);
+    assert_eq!(vdda_from_vrefint(FULL_SCALE as u16), VREFINT_MV);
+    // Nothing came out of the ADC
+    assert_eq!(vdda_from_vrefint(0), 0);
+  }
+
+  #[test]
+  fn millivolts() {
+    assert_eq!(to_millivolts(0, 3300), 0);
+    assert_eq!(to_millivolts(4095, 3300), 3300);
+    assert_eq!(to_millivolts(2048, 3300), 1650);
+  }
+
+  #[test]
+  fn temperature() {
+    assert_eq!(temperature_from_vsense(V25_MV), 250);
+    // The voltage goes down as it gets warmer, by 4.3mV/°C
+    assert_eq!(temperature_from_vsense(V25_MV - 43), 350);
+    assert_eq!(temperature_from_vsense(V25_MV + 43), 150);
+    assert_eq!(temperature_from_vsense(1537), 2);
+    assert_eq!(temperature_from_vsense(1600), -145);
+  }
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
@@ -0,0 +1,57 @@
+//
+// afio.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:05:24 +0000 (UTC)
+//
+
+use mmio;
+use gpio;
+
+/// Base address of the AFIO block
+const AFIO: u32 = 0x4001_0000;
+/// AF remap and debug I/O configuration register
+///
+/// Mind that the SWJ_CFG bits (26:24) are write-only and read as zero, so
+/// every read-modify-write of this register puts them back at their reset
+/// value (full SWJ)
+const AFIO_MAPR: u32 = AFIO + 0x04;
+
+/// External interrupt configuration registers (four of them, each one selects
+/// the port for four EXTI lines)
+const AFIO_EXTICR1: u32 = AFIO + 0x08;
+
+/// Peripherals which can have their pins moved somewhere else (the values are
+/// the corresponding bits in AFIO_MAPR)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Remap {
+  /// TX/RX: PA9/PA10 -> PB6/PB7
+  USART1 = 1 << 2,
+}
+
+/// The AFIO clock has to be enabled for this to have any effect
+pub fn remap(remap: Remap) {
+  mmio::set_bits(AFIO_MAPR, remap as u32);
+}
+
+pub fn unremap(remap: Remap) {
+  mmio::unset_bits(AFIO_MAPR, remap as u32);
+}
+
+pub fn is_remapped(remap: Remap) -> bool {
+  mmio::read(AFIO_MAPR) & remap as u32 != 0
+}
+
+/// Select which port's pin drives the EXTI line (line n can only be driven by
+/// pin n of one of the ports)
+pub fn set_exti_source(line: u8, port: gpio::Gpio) {
+  let reg = AFIO_EXTICR1 + 4 * (line as u32 / 4);
+  let shift = 4 * (line as u32 % 4);
+
+  mmio::write(reg, (mmio::read(reg) & !(0b1111 << shift)) | (port.number() << shift));
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
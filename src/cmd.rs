@@ -13,16 +13,29 @@ use gpio;
 use usart;
 use spi;
 use mcp23s08;
+use i2c;
+use adc;
+use timer;
+use systick;
+use log;
 
-const commands: &'static [(&str, fn (Split<char>))] = &[
-  ("gpio", gpio),
-  ("spi", spi),
-  ("mcp", mcp),
-  ("loadb", loadb),
+/// Command names, their handlers and the keywords they understand (the latter
+/// are only used for completion)
+pub const commands: &'static [(&str, fn (Split<char>), &'static [&'static str])] = &[
+  ("gpio", gpio, &["set", "clear", "mode", "analog", "infloat", "inpp", "outpp", "outdrain", "outaltpp", "outaltdrain"]),
+  ("spi", spi, &["config", "msb", "lsb", "crc"]),
+  ("mcp", mcp, &["write", "read", "dump", "pin", "in", "out", "set", "clear", "get", "pullup", "on", "off"]),
+  ("i2c", i2c, &["scan", "read", "write"]),
+  ("adc", adc, &["temp", "sample", "1.5", "7.5", "13.5", "28.5", "41.5", "55.5", "71.5", "239.5"]),
+  ("pwm", pwm, &["off"]),
+  ("freq", freq, &[]),
+  ("loadb", loadb, &[]),
+  ("uart", uart, &["stats", "clear"]),
+  ("log", log, &["level", "sink", "time", "dump", "off", "error", "warn", "info", "debug", "trace", "default", "console", "ram", "itm", "on"]),
 ];
 
 pub fn lookup_command(cmd: &str) -> Option<fn (Split<char>)> {
-  for &(command, function) in commands {
+  for &(command, function, _) in commands {
     if command == cmd {
       return Some(function)
     }
@@ -31,6 +44,16 @@ pub fn lookup_command(cmd: &str) -> Option<fn (Split<char>)> {
   None
 }
 
+pub fn lookup_keywords(cmd: &str) -> Option<&'static [&'static str]> {
+  for &(command, _, keywords) in commands {
+    if command == cmd {
+      return Some(keywords)
+    }
+  }
+
+  None
+}
+
 fn loadb(mut args: Split<char>) {
   let address = match args.next() {
     Some(address) => i32::from_str_radix(address, 16).ok().unwrap(),
@@ -141,57 +164,932 @@ fn gpio(mut args: Split<char>) {
   }
 }
 
+fn spi_usage() {
+  print!("Usage: spi <1|2> <output value> [output value...]\r\n");
+  print!("Usage: spi <1|2> config <0-3> <frequency> [8|16] [msb|lsb] [crc <polynomial>]\r\n");
+}
+
 fn spi(mut args: Split<char>) {
   let spi = match args.next() {
     Some("1") => spi::SPI1,
+    Some("2") => spi::SPI2,
     Some(_) | None => {
-      print!("Usage: spi <1> <output value>\r\n");
+      spi_usage();
       return;
     },
   };
 
-  let value = match args.next() {
-    Some(value) => u8::from_str_radix(value, 16).ok().unwrap(),
+  let mut buf = [0u8; 16];
+  let mut len = 0;
+
+  match args.next() {
+    Some("config") => {
+      spi_config(spi, args);
+      return;
+    },
+    Some(value) => match u8::from_str_radix(value, 16) {
+      Ok(byte) => {
+        buf[len] = byte;
+        len += 1;
+      },
+      Err(_) => {
+        spi_usage();
+        return;
+      },
+    },
     None => {
-      print!("Usage: spi <1> <output value>\r\n");
+      spi_usage();
+      return;
+    },
+  };
+
+  for value in args {
+    if len == buf.len() {
+      print!("At most {} bytes can be sent at once\r\n", buf.len());
+      return;
+    }
+
+    buf[len] = match u8::from_str_radix(value, 16) {
+      Ok(byte) => byte,
+      Err(_) => {
+        spi_usage();
+        return;
+      },
+    };
+    len += 1;
+  }
+
+  // Nothing would ever clock the bytes out
+  if !spi.is_initialized() {
+    print!("The SPI isn't configured as a master, use 'spi <1|2> config' first\r\n");
+    return;
+  }
+
+  // The CRC goes out and gets checked automatically once it's configured
+  // The bus may be in the middle of an asynchronous transfer
+  let result = spi.transaction(|bus| {
+    if bus.is_crc_enabled() {
+      bus.transfer_in_place_crc(&mut buf[..len])
+    } else {
+      bus.transfer_in_place(&mut buf[..len])
+    }
+  }).and_then(|result| result);
+
+  match result {
+    Ok(()) => (),
+    // Nothing went out
+    Err(spi::Error::Busy) => {
+      print!("Failed: Busy\r\n");
+      return;
+    },
+    Err(err) => print!("Failed: {:?}\r\n", err),
+  }
+
+  print!("Returned:");
+  for byte in &buf[..len] {
+    print!(" {:x}", byte);
+  }
+  print!("\r\n");
+}
+
+fn spi_config(spi: spi::SPI, mut args: Split<char>) {
+  let mode = match args.next() {
+    Some("0") => spi::Mode::Mode0,
+    Some("1") => spi::Mode::Mode1,
+    Some("2") => spi::Mode::Mode2,
+    Some("3") => spi::Mode::Mode3,
+    Some(_) | None => {
+      spi_usage();
+      return;
+    },
+  };
+
+  let frequency = match args.next().map(|frequency| frequency.parse::<u32>()) {
+    Some(Ok(frequency)) => frequency,
+    Some(Err(_)) | None => {
+      spi_usage();
+      return;
+    },
+  };
+
+  let frame_size = match args.next() {
+    Some("8") | None => spi::FrameSize::Bits8,
+    Some("16") => spi::FrameSize::Bits16,
+    Some(_) => {
+      spi_usage();
       return;
     },
   };
 
-  let input_value = spi.send_recv_byte(value);
+  let bit_order = match args.next() {
+    Some("msb") | None => spi::BitOrder::MsbFirst,
+    Some("lsb") => spi::BitOrder::LsbFirst,
+    Some(_) => {
+      spi_usage();
+      return;
+    },
+  };
 
-  print!("Returned: {:x}\r\n", input_value);
+  let crc = match args.next() {
+    Some("crc") => match args.next().map(|polynomial| u16::from_str_radix(polynomial, 16)) {
+      Some(Ok(polynomial)) => Some(polynomial),
+      Some(Err(_)) | None => {
+        spi_usage();
+        return;
+      },
+    },
+    None => None,
+    Some(_) => {
+      spi_usage();
+      return;
+    },
+  };
+
+  let config = spi::SpiConfig {
+    mode: mode,
+    frequency: frequency,
+    frame_size: frame_size,
+    bit_order: bit_order,
+    crc: crc,
+  };
+
+  // Sets up the pins as well, in case the bus wasn't used before
+  match spi.transaction(|bus| bus.initialize(&config)) {
+    Ok(frequency) => print!("SCK = {} Hz\r\n", frequency),
+    Err(err) => print!("Failed: {:?}\r\n", err),
+  }
+}
+
+fn mcp_usage() {
+  print!("Usage: mcp [address] write <reg> <value>\r\n");
+  print!("Usage: mcp [address] read <reg>\r\n");
+  print!("Usage: mcp [address] dump\r\n");
+  print!("Usage: mcp [address] pin <0-7> <in|out|set|clear|get|pullup [on|off]>\r\n");
+}
+
+/// The registers' names and what their set bits mean (in the order of their
+/// addresses)
+const mcp_registers: [(&'static str, &'static str); 11] = [
+  ("IODIR", "inputs"),
+  ("IPOL", "inverted"),
+  ("GPINTEN", "interrupt enabled"),
+  ("DEFVAL", "default high"),
+  ("INTCON", "compared to DEFVAL"),
+  ("IOCON", ""),
+  ("GPPU", "pulled up"),
+  ("INTF", "flagged"),
+  ("INTCAP", "captured high"),
+  ("GPIO", "high"),
+  ("OLAT", "latched high"),
+];
+
+/// IOCON's bits (from bit 7 down, unimplemented ones are empty)
+const mcp_iocon_bits: [&'static str; 8] = ["", "", "SEQOP", "DISSLW", "HAEN", "ODR", "INTPOL", ""];
+
+fn mcp_dump(chip: &mcp23s08::Mcp23s08) {
+  for reg in 0..mcp_registers.len() {
+    let (name, meaning) = mcp_registers[reg];
+
+    let value = match chip.read_reg(reg as u8) {
+      Ok(value) => value,
+      Err(err) => {
+        print!("Failed: {:?}\r\n", err);
+        return;
+      },
+    };
+
+    print!("{:02x} {:8} 0x{:02x} {:08b} ", reg, name, value, value);
+
+    if reg as u8 == mcp23s08::IOCON {
+      for bit in 0..8 {
+        if value & 1 << (7 - bit) != 0 && mcp_iocon_bits[bit] != "" {
+          print!(" {}", mcp_iocon_bits[bit]);
+        }
+      }
+    } else {
+      print!(" {}:", meaning);
+
+      if value == 0 {
+        print!(" -");
+      }
+
+      for pin in 0..8 {
+        if value & 1 << pin != 0 {
+          print!(" {}", pin);
+        }
+      }
+    }
+
+    print!("\r\n");
+  }
+}
+
+fn mcp_pin(chip: &mcp23s08::Mcp23s08, mut args: Split<char>) {
+  let pin = match args.next().map(|pin| pin.parse::<u8>()) {
+    Some(Ok(pin)) if pin < 8 => pin,
+    _ => {
+      mcp_usage();
+      return;
+    },
+  };
+
+  let result = match args.next() {
+    Some("in") => chip.set_direction(pin, mcp23s08::Direction::Input),
+    Some("out") => chip.set_direction(pin, mcp23s08::Direction::Output),
+    Some("set") => chip.set_output(pin, true),
+    Some("clear") => chip.set_output(pin, false),
+    Some("get") => chip.get_input(pin).map(|high| {
+      print!("{}\r\n", if high { "high" } else { "low" });
+    }),
+    Some("pullup") => {
+      let enabled = match args.next() {
+        None => true,
+        arg => match on_off(arg) {
+          Some(enabled) => enabled,
+          None => {
+            mcp_usage();
+            return;
+          },
+        },
+      };
+
+      chip.set_pull_up(pin, enabled)
+    },
+    Some(_) | None => {
+      mcp_usage();
+      return;
+    },
+  };
+
+  match result {
+    Ok(()) => (),
+    Err(err) => print!("Failed: {:?}\r\n", err),
+  }
 }
 
 fn mcp(mut args: Split<char>) {
-  #[derive(PartialEq)]
-  enum Op { Write };
+  let mut op = args.next();
 
-  let op = match args.next() {
-    Some("write") => Op::Write,
-    Some (_) | None => {
-      print!("Usage: mcp write <reg> <value>\r\n");
+  // The chips on the board's bus, told apart by their A1:A0
+  let chip = match op.map(|address| address.parse::<u8>()) {
+    Some(Ok(address)) if address < 4 => {
+      op = args.next();
+      mcp23s08::Mcp23s08::new(mcp23s08::SPI_DEVICE, address)
+    },
+    Some(Ok(_)) => {
+      mcp_usage();
       return;
     },
+    Some(Err(_)) | None => mcp23s08::DEVICE,
   };
 
-  let reg = match args.next() {
-    Some(reg) => u8::from_str_radix(reg, 16).ok().unwrap(),
+  match op {
+    Some("dump") => mcp_dump(&chip),
+    Some("pin") => mcp_pin(&chip, args),
+    Some("read") => {
+      let reg = match args.next().map(|reg| u8::from_str_radix(reg, 16)) {
+        Some(Ok(reg)) => reg,
+        _ => {
+          mcp_usage();
+          return;
+        },
+      };
+
+      match chip.read_reg(reg) {
+        Ok(value) => print!("0x{:02x}\r\n", value),
+        Err(err) => print!("Failed: {:?}\r\n", err),
+      }
+    },
+    Some("write") => {
+      let reg = match args.next().map(|reg| u8::from_str_radix(reg, 16)) {
+        Some(Ok(reg)) => reg,
+        _ => {
+          mcp_usage();
+          return;
+        },
+      };
+
+      let value = match args.next().map(|value| u8::from_str_radix(value, 16)) {
+        Some(Ok(value)) => value,
+        _ => {
+          mcp_usage();
+          return;
+        },
+      };
+
+      match chip.write_reg(reg, value) {
+        Ok(()) => (),
+        Err(err) => print!("Failed: {:?}\r\n", err),
+      }
+    },
+    Some(_) | None => mcp_usage(),
+  }
+}
+
+fn i2c_usage() {
+  print!("Usage: i2c <1|2> scan\r\n");
+  print!("Usage: i2c <1|2> read <address> <count>\r\n");
+  print!("Usage: i2c <1|2> write <address> <byte> [byte...]\r\n");
+}
+
+/// Print the addresses that got acknowledged, i2cdetect-style
+fn i2c_scan(bus: i2c::I2C) {
+  print!("   ");
+  for column in 0..16 {
+    print!("  {:x}", column);
+  }
+
+  // 0x00-0x07 and 0x78-0x7f are reserved
+  for address in 0..0x80u8 {
+    if address % 16 == 0 {
+      print!("\r\n{:02x}:", address);
+    }
+
+    if address < 0x08 || address > 0x77 {
+      print!("   ");
+      continue;
+    }
+
+    match bus.probe(address) {
+      Ok(true) => print!(" {:02x}", address),
+      Ok(false) => print!(" --"),
+      Err(err) => {
+        print!("\r\nFailed: {:?}\r\n", err);
+        return;
+      },
+    }
+  }
+
+  print!("\r\n");
+}
+
+fn i2c(mut args: Split<char>) {
+  let bus = match args.next() {
+    Some("1") => i2c::I2C1,
+    Some("2") => i2c::I2C2,
+    Some(_) | None => {
+      i2c_usage();
+      return;
+    },
+  };
+
+  let op = args.next();
+
+  if op == Some("scan") {
+    if !bus.is_initialized() {
+      bus.initialize(i2c::Speed::Standard);
+    }
+
+    i2c_scan(bus);
+    return;
+  }
+
+  let address = match args.next().map(|address| u8::from_str_radix(address, 16)) {
+    Some(Ok(address)) if address < 0x80 => address,
+    _ => {
+      i2c_usage();
+      return;
+    },
+  };
+
+  let mut buf = [0u8; 16];
+  let mut len = 0;
+
+  match op {
+    Some("read") => {
+      len = match args.next().map(|count| count.parse::<usize>()) {
+        Some(Ok(count)) if count > 0 && count <= buf.len() => count,
+        _ => {
+          print!("Between 1 and {} bytes can be read at once\r\n", buf.len());
+          return;
+        },
+      };
+    },
+    Some("write") => {
+      for value in args {
+        if len == buf.len() {
+          print!("At most {} bytes can be written at once\r\n", buf.len());
+          return;
+        }
+
+        buf[len] = match u8::from_str_radix(value, 16) {
+          Ok(byte) => byte,
+          Err(_) => {
+            i2c_usage();
+            return;
+          },
+        };
+        len += 1;
+      }
+
+      if len == 0 {
+        i2c_usage();
+        return;
+      }
+    },
+    Some(_) | None => {
+      i2c_usage();
+      return;
+    },
+  }
+
+  if !bus.is_initialized() {
+    bus.initialize(i2c::Speed::Standard);
+  }
+
+  let result = if op == Some("read") {
+    bus.read(address, &mut buf[..len])
+  } else {
+    bus.write(address, &buf[..len])
+  };
+
+  match result {
+    Ok(()) if op == Some("read") => {
+      print!("Returned:");
+      for byte in &buf[..len] {
+        print!(" {:x}", byte);
+      }
+      print!("\r\n");
+    },
+    Ok(()) => (),
+    Err(err) => print!("Failed: {:?}\r\n", err),
+  }
+}
+
+fn adc_usage() {
+  print!("Usage: adc <1|2> <channel> [channel...]\r\n");
+  print!("Usage: adc <1|2> sample <channel> <1.5|7.5|13.5|28.5|41.5|55.5|71.5|239.5>\r\n");
+  print!("Usage: adc temp\r\n");
+}
+
+/// Parse the channel number, making sure that the ADC can convert it
+fn adc_channel(adc: adc::ADC, channel: Option<&str>) -> Option<u8> {
+  let channel = match channel.map(|channel| channel.parse::<u8>()) {
+    Some(Ok(channel)) if channel <= adc::VREFINT => channel,
+    _ => return None,
+  };
+
+  // Don't take the pins away from the peripherals (like PA2/PA3 from the
+  // console)
+  if let Some((port, pin)) = adc::channel_pin(channel) {
+    if let Some(user) = pin_user(port, pin) {
+      print!("P{}{} is taken by {}\r\n", (b'A' + port.number() as u8) as char, pin, user);
+      return None;
+    }
+  }
+
+  if channel >= adc::TEMPERATURE && adc != adc::ADC1 {
+    print!("The temperature sensor and Vrefint are only on ADC1\r\n");
+    return None;
+  }
+
+  Some(channel)
+}
+
+fn adc(mut args: Split<char>) {
+  let adc = match args.next() {
+    Some("1") => adc::ADC1,
+    Some("2") => adc::ADC2,
+    Some("temp") => {
+      match (adc::read_temperature(), adc::read_vdda()) {
+        (Ok(temperature), Ok(vdda)) => {
+          print!("Temperature = {}.{} C\r\n", temperature / 10, (temperature % 10).abs());
+          print!("VDDA = {} mV\r\n", vdda);
+        },
+        (Err(err), _) | (_, Err(err)) => print!("Failed: {:?}\r\n", err),
+      }
+      return;
+    },
+    Some(_) | None => {
+      adc_usage();
+      return;
+    },
+  };
+
+  if !adc.is_initialized() {
+    adc.initialize();
+  }
+
+  let mut channels = [0u8; 16];
+  let mut len = 0;
+
+  let mut arg = args.next();
+
+  if arg == Some("sample") {
+    let channel = match adc_channel(adc, args.next()) {
+      Some(channel) => channel,
+      None => {
+        adc_usage();
+        return;
+      },
+    };
+
+    let time = match args.next() {
+      Some("1.5") => adc::SampleTime::Cycles1_5,
+      Some("7.5") => adc::SampleTime::Cycles7_5,
+      Some("13.5") => adc::SampleTime::Cycles13_5,
+      Some("28.5") => adc::SampleTime::Cycles28_5,
+      Some("41.5") => adc::SampleTime::Cycles41_5,
+      Some("55.5") => adc::SampleTime::Cycles55_5,
+      Some("71.5") => adc::SampleTime::Cycles71_5,
+      Some("239.5") => adc::SampleTime::Cycles239_5,
+      Some(_) | None => {
+        adc_usage();
+        return;
+      },
+    };
+
+    adc.set_sample_time(channel, time);
+    return;
+  }
+
+  while arg.is_some() {
+    if len == channels.len() {
+      print!("At most {} channels can be converted at once\r\n", channels.len());
+      return;
+    }
+
+    channels[len] = match adc_channel(adc, arg) {
+      Some(channel) => channel,
+      None => {
+        adc_usage();
+        return;
+      },
+    };
+    len += 1;
+
+    arg = args.next();
+  }
+
+  if len == 0 {
+    adc_usage();
+    return;
+  }
+
+  for &channel in &channels[..len] {
+    adc.configure_pin(channel);
+  }
+
+  let mut results = [0u16; 16];
+
+  let vdda = match adc.scan(&channels[..len], &mut results[..len]).and_then(|()| adc::read_vdda()) {
+    Ok(vdda) => vdda,
+    Err(err) => {
+      print!("Failed: {:?}\r\n", err);
+      return;
+    },
+  };
+
+  for i in 0..len {
+    print!("{:2}: {:4} ({} mV)\r\n", channels[i], results[i], adc::to_millivolts(results[i], vdda));
+  }
+}
+
+/// Which of the enabled peripherals uses the pin, if any
+fn pin_user(port: gpio::Gpio, pin: u8) -> Option<&'static str> {
+  let users = [
+    (usart::USART1.owns_pin(port, pin), "USART1"),
+    (usart::USART2.owns_pin(port, pin), "USART2"),
+    (usart::USART3.owns_pin(port, pin), "USART3"),
+    (spi::SPI1.owns_pin(port, pin), "SPI1"),
+    (spi::SPI2.owns_pin(port, pin), "SPI2"),
+    (i2c::I2C1.owns_pin(port, pin), "I2C1"),
+    (i2c::I2C2.owns_pin(port, pin), "I2C2"),
+  ];
+
+  for &(owns, name) in &users {
+    if owns {
+      return Some(name);
+    }
+  }
+
+  None
+}
+
+fn pwm_usage() {
+  print!("Usage: pwm <1|2|3|4> <1-4> <frequency> <duty %> [dead time ns]\r\n");
+  print!("Usage: pwm <1|2|3|4> <1-4> off\r\n");
+  print!("(the dead time turns the complementary output of TIM1 CH1-3 on)\r\n");
+}
+
+fn pwm(mut args: Split<char>) {
+  let tim = match args.next() {
+    Some("1") => timer::TIM1,
+    Some("2") => timer::TIM2,
+    Some("3") => timer::TIM3,
+    Some("4") => timer::TIM4,
+    Some(_) | None => {
+      pwm_usage();
+      return;
+    },
+  };
+
+  let channel = match args.next().map(|channel| channel.parse::<u8>()) {
+    Some(Ok(channel)) if channel >= 1 && channel <= 4 => channel,
+    _ => {
+      pwm_usage();
+      return;
+    },
+  };
+
+  let frequency = match args.next() {
+    Some("off") => {
+      tim.stop_pwm(channel);
+      return;
+    },
+    Some(frequency) => match frequency.parse::<u32>() {
+      Ok(frequency) if frequency > 0 => frequency,
+      _ => {
+        pwm_usage();
+        return;
+      },
+    },
     None => {
-      print!("Usage: mcp write <reg> <value>\r\n");
+      pwm_usage();
+      return;
+    },
+  };
+
+  let duty = match args.next().map(|duty| duty.parse::<u8>()) {
+    Some(Ok(duty)) if duty <= 100 => duty,
+    _ => {
+      pwm_usage();
+      return;
+    },
+  };
+
+  let dead_time = match args.next().map(|ns| ns.parse::<u32>()) {
+    Some(Ok(ns)) if tim.complementary_pin(channel).is_some() => Some(ns),
+    Some(Ok(_)) => {
+      print!("Only TIM1 channels 1-3 have complementary outputs\r\n");
+      return;
+    },
+    Some(Err(_)) => {
+      pwm_usage();
+      return;
+    },
+    None => None,
+  };
+
+  // Don't take the pins away from the peripherals (like PA2/PA3 from the
+  // console)
+  let negated = if dead_time.is_some() { tim.complementary_pin(channel) } else { None };
+
+  for output in [tim.channel_pin(channel), negated].iter() {
+    if let Some((port, pin)) = *output {
+      if let Some(user) = pin_user(port, pin) {
+        print!("P{}{} is taken by {}\r\n", (b'A' + port.number() as u8) as char, pin, user);
+        return;
+      }
+    }
+  }
+
+  // A timer counting for start() or the input capture isn't ours to change
+  if tim.is_running() && !tim.is_pwm_running() {
+    print!("The timer is already in use\r\n");
+    return;
+  }
+
+  // The other channels of the timer get the new frequency too
+  let actual = if tim.is_running() {
+    tim.set_frequency(frequency)
+  } else {
+    tim.initialize(frequency)
+  };
+
+  if let Some(ns) = dead_time {
+    print!("Dead time = {} ns\r\n", tim.set_dead_time(ns));
+  }
+
+  tim.start_pwm(channel, dead_time.is_some());
+  tim.set_duty(channel, duty);
+
+  print!("Frequency = {} Hz\r\n", actual);
+}
+
+/// Measure the frequency and the duty cycle of the signal on the pin (it has
+/// to be channel 1 or 2 of one of the timers)
+fn freq(mut args: Split<char>) {
+  let name = match args.next() {
+    Some(name) if name.len() >= 3 => name,
+    _ => {
+      print!("Usage: freq <pin> (eg. freq PA0)\r\n");
       return;
     },
   };
 
-  let value = match args.next() {
-    Some(value) => u8::from_str_radix(value, 16).ok().unwrap(),
+  let port = match &name[..2] {
+    "PA" | "pa" => gpio::GPIOA,
+    "PB" | "pb" => gpio::GPIOB,
+    _ => {
+      print!("Usage: freq <pin> (eg. freq PA0)\r\n");
+      return;
+    },
+  };
+
+  let pin = match name[2..].parse::<u8>() {
+    Ok(pin) if pin <= 15 => pin,
+    _ => {
+      print!("Usage: freq <pin> (eg. freq PA0)\r\n");
+      return;
+    },
+  };
+
+  let (tim, channel) = match timer::capture_input(port, pin) {
+    Some(input) => input,
     None => {
-      print!("Usage: mcp write <reg> <value>\r\n");
+      print!("{} isn't an input capture pin (PA8, PA9, PA0, PA1, PA6, PA7, PB6, PB7)\r\n", name);
       return;
     },
   };
 
-  mcp23s08::write_reg(spi::SPI1, reg, value);
+  // The capture leaves the pin a floating input
+  if let Some(user) = pin_user(port, pin) {
+    print!("{} is taken by {}\r\n", name, user);
+    return;
+  }
+
+  if tim.is_running() {
+    print!("The timer of {} is already in use\r\n", name);
+    return;
+  }
+
+  // Microsecond ticks, the overflows take care of the slow signals
+  tim.start_capture(channel, 1_000_000);
+
+  let start = systick::millis();
+  let mut capture = None;
+
+  // Two rising edges are needed, so the signals below 1 Hz need a while
+  while capture.is_none() && systick::millis().wrapping_sub(start) < 3000 {
+    capture = tim.read_capture();
+  }
+
+  // 1 MHz, as long as the timer's clock is a whole number of MHz
+  let tick_mhz = tim.tick_frequency() / 1_000_000;
+
+  tim.stop_capture();
+
+  let capture = match capture {
+    Some(capture) if tick_mhz > 0 && capture.period >= tick_mhz => capture,
+    _ => {
+      print!("No signal on {}\r\n", name);
+      return;
+    },
+  };
+
+  let period_us = capture.period / tick_mhz;
+  let high_us = capture.high / tick_mhz;
+
+  // The periods are below 3 s (3e6 us) here, so none of these overflow
+  let millihertz = 1_000_000 / period_us * 1000 + 1_000_000 % period_us * 1000 / period_us;
+  let duty = high_us * 1000 / period_us;
+
+  print!("Frequency = {}.{:03} Hz\r\n", millihertz / 1000, millihertz % 1000);
+  print!("Period    = {} us\r\n", period_us);
+  print!("High      = {} us\r\n", high_us);
+  print!("Duty      = {}.{} %\r\n", duty / 10, duty % 10);
+}
+
+fn uart(mut args: Split<char>) {
+  match args.next() {
+    Some("stats") => (),
+    Some(_) | None => {
+      print!("Usage: uart stats [1|2|3] [clear]\r\n");
+      return;
+    },
+  };
+
+  let port = match args.next() {
+    Some("1") => Some((usart::USART1, "USART1")),
+    Some("2") => Some((usart::USART2, "USART2")),
+    Some("3") => Some((usart::USART3, "USART3")),
+    None => None,
+    Some(_) => {
+      print!("Usage: uart stats [1|2|3] [clear]\r\n");
+      return;
+    },
+  };
+
+  let clear = match args.next() {
+    Some("clear") => true,
+    None => false,
+    Some(_) => {
+      print!("Usage: uart stats [1|2|3] [clear]\r\n");
+      return;
+    },
+  };
+
+  match port {
+    Some((port, name)) => uart_stats(port, name, clear),
+    None => {
+      uart_stats(usart::USART1, "USART1", clear);
+      uart_stats(usart::USART2, "USART2", clear);
+      uart_stats(usart::USART3, "USART3", clear);
+    },
+  }
+}
+
+fn uart_stats(port: usart::Usart, name: &str, clear: bool) {
+  if clear {
+    port.clear_stats();
+    print!("{}: statistics cleared\r\n", name);
+    return;
+  }
+
+  let stats = port.stats();
+
+  print!("{}: rx {} tx {} overrun {} framing {} noise {} parity {}\r\n",
+         name, stats.received, stats.sent, stats.overrun, stats.framing, stats.noise, stats.parity);
+}
+
+fn log_usage() {
+  print!("Usage: log\r\n");
+  print!("Usage: log level <off|error|warn|info|debug|trace|default> [module]\r\n");
+  print!("Usage: log sink <console|ram|itm> <on|off>\r\n");
+  print!("Usage: log time <on|off>\r\n");
+  print!("Usage: log dump\r\n");
+}
+
+fn on_off(arg: Option<&str>) -> Option<bool> {
+  match arg {
+    Some("on") => Some(true),
+    Some("off") => Some(false),
+    _ => None,
+  }
+}
+
+fn log(mut args: Split<char>) {
+  match args.next() {
+    None => {
+      print!("Default level: {:?}\r\n", log::default_level());
+      log::for_each_filter(|module, level| {
+        print!("  {}: {:?}\r\n", module, level);
+      });
+      print!("Sinks: console {} ram {} itm {}\r\n",
+             log::is_sink_enabled(log::Sink::Console),
+             log::is_sink_enabled(log::Sink::Ram),
+             log::is_sink_enabled(log::Sink::Itm));
+      print!("Timestamps: {}\r\n", log::timestamps_enabled());
+      print!("Dropped on the console: {}\r\n", log::console_dropped());
+    },
+    Some("level") => {
+      let level = match args.next() {
+        Some("default") => None,
+        Some(name) => match log::Level::from_str(name) {
+          Some(level) => Some(level),
+          None => {
+            log_usage();
+            return;
+          },
+        },
+        None => {
+          log_usage();
+          return;
+        },
+      };
+
+      match (args.next(), level) {
+        (Some(module), Some(level)) => {
+          if !log::set_level(module, level) {
+            print!("Too many modules with their own level (or the name is too long)\r\n");
+          }
+        },
+        (Some(module), None) => log::clear_level(module),
+        (None, Some(level)) => log::set_default_level(level),
+        (None, None) => log_usage(),
+      }
+    },
+    Some("sink") => {
+      let sink = match args.next() {
+        Some("console") => log::Sink::Console,
+        Some("ram") => log::Sink::Ram,
+        Some("itm") => log::Sink::Itm,
+        _ => {
+          log_usage();
+          return;
+        },
+      };
+
+      match on_off(args.next()) {
+        Some(true) => log::enable_sink(sink),
+        Some(false) => log::disable_sink(sink),
+        None => log_usage(),
+      }
+    },
+    Some("time") => {
+      match on_off(args.next()) {
+        Some(enabled) => log::set_timestamps(enabled),
+        None => log_usage(),
+      }
+    },
+    Some("dump") => {
+      while let Some(byte) = log::pop_ram() {
+        print!("{}", byte as char);
+      }
+    },
+    Some(_) => log_usage(),
+  }
 }
 
 /*
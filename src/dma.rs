@@ -0,0 +1,285 @@
+//
+// dma.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:13:19 +0000 (UTC)
+//
+
+use mmio;
+use nvic;
+
+/// Base address of the DMA1 block
+const DMA1: u32 = 0x4002_0000;
+/// Interrupt status register (4 bits per channel)
+const DMA_ISR: u32 = DMA1 + 0x00;
+/// Interrupt flag clear register (4 bits per channel)
+const DMA_IFCR: u32 = DMA1 + 0x04;
+/// Transfer complete flag
+const DMA_ISR_TCIF: u32 = 1 << 1;
+/// Half transfer flag
+const DMA_ISR_HTIF: u32 = 1 << 2;
+/// Transfer error flag
+const DMA_ISR_TEIF: u32 = 1 << 3;
+/// Global interrupt flag (clearing it clears the three others)
+const DMA_ISR_GIF: u32 = 1 << 0;
+
+/// Channel enable
+const DMA_CCR_EN: u32 = 1 << 0;
+/// Transfer complete interrupt enable
+const DMA_CCR_TCIE: u32 = 1 << 1;
+/// Half transfer interrupt enable
+const DMA_CCR_HTIE: u32 = 1 << 2;
+/// Transfer error interrupt enable
+const DMA_CCR_TEIE: u32 = 1 << 3;
+/// Data transfer direction
+///  0: read from peripheral
+///  1: read from memory
+const DMA_CCR_DIR: u32 = 1 << 4;
+/// Circular mode
+const DMA_CCR_CIRC: u32 = 1 << 5;
+/// Memory increment mode
+const DMA_CCR_MINC: u32 = 1 << 7;
+/// Peripheral size
+///  00: 8 bits
+///  01: 16 bits
+///  10: 32 bits
+const DMA_CCR_PSIZE_SHIFT: u32 = 8;
+/// Memory size (same encoding as PSIZE)
+const DMA_CCR_MSIZE_SHIFT: u32 = 10;
+/// Channel priority level
+///  00: low
+///  01: medium
+///  10: high
+///  11: very high
+const DMA_CCR_PL_SHIFT: u32 = 12;
+
+#[repr(packed)]
+struct Channel_register_map {
+  CCR:   u32,
+  CNDTR: u32,
+  CPAR:  u32,
+  CMAR:  u32,
+  _reserved: u32,
+}
+
+/// One of the seven DMA1 channels (1-7)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Channel(u32);
+
+pub const CHANNEL1: Channel = Channel(1);
+pub const CHANNEL2: Channel = Channel(2);
+pub const CHANNEL3: Channel = Channel(3);
+pub const CHANNEL4: Channel = Channel(4);
+pub const CHANNEL5: Channel = Channel(5);
+pub const CHANNEL6: Channel = Channel(6);
+pub const CHANNEL7: Channel = Channel(7);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+  PeripheralToMemory,
+  MemoryToPeripheral,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+  Bits8 = 0b00,
+  Bits16 = 0b01,
+  Bits32 = 0b10,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+  Low = 0b00,
+  Medium = 0b01,
+  High = 0b10,
+  VeryHigh = 0b11,
+}
+
+/// What the channel's callback gets notified about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+  HalfComplete,
+  Complete,
+  Error,
+}
+
+/// What a channel should do
+#[derive(Debug, Clone, Copy)]
+pub struct Transfer {
+  /// Address of the peripheral's data register
+  pub peripheral: u32,
+  /// Address of the buffer
+  pub memory: u32,
+  /// In units of `size`
+  pub count: u16,
+  pub direction: Direction,
+  /// Set to false to keep using the same memory location (eg. to send the
+  /// same dummy byte over and over)
+  pub memory_increment: bool,
+  pub size: Size,
+  /// Start over once done (the callback gets notified at the half and at
+  /// the end of the buffer)
+  pub circular: bool,
+  pub priority: Priority,
+}
+
+/// Bit n is set when channel n is in use
+static mut claimed: u32 = 0;
+static mut callbacks: [Option<fn(Event)>; 7] = [None; 7];
+
+impl Channel {
+  fn regmap(&self) -> *mut Channel_register_map {
+    (DMA1 + 0x08 + 20 * (self.0 - 1)) as *mut Channel_register_map
+  }
+
+  fn index(&self) -> usize {
+    (self.0 - 1) as usize
+  }
+
+  fn flag(&self, flag: u32) -> u32 {
+    flag << (4 * (self.0 - 1))
+  }
+
+  fn irq(&self) -> nvic::Irq {
+    match self.0 {
+      1 => nvic::Irq::DMA1_CHANNEL1,
+      2 => nvic::Irq::DMA1_CHANNEL2,
+      3 => nvic::Irq::DMA1_CHANNEL3,
+      4 => nvic::Irq::DMA1_CHANNEL4,
+      5 => nvic::Irq::DMA1_CHANNEL5,
+      6 => nvic::Irq::DMA1_CHANNEL6,
+      _ => nvic::Irq::DMA1_CHANNEL7,
+    }
+  }
+
+  /// Reserve the channel, returns false if somebody else already did
+  pub fn claim(&self) -> bool {
+    nvic::without_interrupts(|| unsafe {
+      if claimed & (1 << self.0) != 0 {
+        return false;
+      }
+
+      claimed |= 1 << self.0;
+      true
+    })
+  }
+
+  pub fn release(&self) {
+    self.stop();
+
+    nvic::without_interrupts(|| unsafe {
+      claimed &= !(1 << self.0);
+    });
+  }
+
+  /// The callback is run from the interrupt handler, passing None makes the
+  /// channel not raise any interrupts
+  pub fn set_callback(&self, callback: Option<fn(Event)>) {
+    unsafe {
+      callbacks[self.index()] = callback;
+    }
+
+    match callback {
+      Some(_) => nvic::enable_irq(self.irq()),
+      None => nvic::disable_irq(self.irq()),
+    }
+  }
+
+  /// Program the channel (it has to be stopped) and start it
+  pub fn start(&self, transfer: &Transfer) {
+    let regmap = self.regmap();
+
+    let mut ccr = (transfer.size as u32) << DMA_CCR_PSIZE_SHIFT
+                | (transfer.size as u32) << DMA_CCR_MSIZE_SHIFT
+                | (transfer.priority as u32) << DMA_CCR_PL_SHIFT;
+
+    if transfer.direction == Direction::MemoryToPeripheral {
+      ccr |= DMA_CCR_DIR;
+    }
+
+    if transfer.memory_increment {
+      ccr |= DMA_CCR_MINC;
+    }
+
+    if transfer.circular {
+      ccr |= DMA_CCR_CIRC;
+    }
+
+    if unsafe { callbacks[self.index()].is_some() } {
+      ccr |= DMA_CCR_TCIE | DMA_CCR_TEIE;
+
+      if transfer.circular {
+        ccr |= DMA_CCR_HTIE;
+      }
+    }
+
+    mmio::write(DMA_IFCR, self.flag(DMA_ISR_GIF));
+
+    unsafe {
+      (*regmap).CPAR = transfer.peripheral;
+      (*regmap).CMAR = transfer.memory;
+      (*regmap).CNDTR = transfer.count as u32;
+      (*regmap).CCR = ccr;
+      (*regmap).CCR |= DMA_CCR_EN;
+    }
+  }
+
+  pub fn stop(&self) {
+    unsafe {
+      (*self.regmap()).CCR &= !DMA_CCR_EN;
+    }
+
+    mmio::write(DMA_IFCR, self.flag(DMA_ISR_GIF));
+  }
+
+  /// Whether all the data went through (or an error stopped the transfer)
+  pub fn is_complete(&self) -> bool {
+    mmio::read(DMA_ISR) & self.flag(DMA_ISR_TCIF | DMA_ISR_TEIF) != 0
+  }
+
+  pub fn has_failed(&self) -> bool {
+    mmio::read(DMA_ISR) & self.flag(DMA_ISR_TEIF) != 0
+  }
+
+  /// How many items are still left to be transferred
+  pub fn remaining(&self) -> u16 {
+    unsafe { (*self.regmap()).CNDTR as u16 }
+  }
+
+  fn handle_irq(self) {
+    let isr = mmio::read(DMA_ISR);
+
+    mmio::write(DMA_IFCR, self.flag(DMA_ISR_GIF));
+
+    let callback = match unsafe { callbacks[self.index()] } {
+      Some(callback) => callback,
+      None => return,
+    };
+
+    if isr & self.flag(DMA_ISR_TEIF) != 0 {
+      callback(Event::Error);
+      return;
+    }
+
+    if isr & self.flag(DMA_ISR_HTIF) != 0 {
+      callback(Event::HalfComplete);
+    }
+
+    if isr & self.flag(DMA_ISR_TCIF) != 0 {
+      callback(Event::Complete);
+    }
+  }
+}
+
+pub extern "C" fn dma1_channel1_irq_handler() { CHANNEL1.handle_irq(); }
+pub extern "C" fn dma1_channel2_irq_handler() { CHANNEL2.handle_irq(); }
+pub extern "C" fn dma1_channel3_irq_handler() { CHANNEL3.handle_irq(); }
+pub extern "C" fn dma1_channel4_irq_handler() { CHANNEL4.handle_irq(); }
+pub extern "C" fn dma1_channel5_irq_handler() { CHANNEL5.handle_irq(); }
+pub extern "C" fn dma1_channel6_irq_handler() { CHANNEL6.handle_irq(); }
+pub extern "C" fn dma1_channel7_irq_handler() { CHANNEL7.handle_irq(); }
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
@@ -0,0 +1,121 @@
+//
+// exti.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:16:20 +0000 (UTC)
+//
+
+use mmio;
+use nvic;
+use afio;
+use gpio;
+
+/// Base address of the EXTI block
+const EXTI: u32 = 0x4001_0400;
+/// Interrupt mask register
+const EXTI_IMR: u32 = EXTI + 0x00;
+/// Rising trigger selection register
+const EXTI_RTSR: u32 = EXTI + 0x08;
+/// Falling trigger selection register
+const EXTI_FTSR: u32 = EXTI + 0x0c;
+/// Pending register (cleared by writing a 1)
+const EXTI_PR: u32 = EXTI + 0x14;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+  Rising,
+  Falling,
+  Both,
+}
+
+/// What gets called when the line fires (one per GPIO line)
+static mut handlers: [Option<fn(u8)>; 16] = [None; 16];
+
+fn irq(line: u8) -> nvic::Irq {
+  match line {
+    0 => nvic::Irq::EXTI0,
+    1 => nvic::Irq::EXTI1,
+    2 => nvic::Irq::EXTI2,
+    3 => nvic::Irq::EXTI3,
+    4 => nvic::Irq::EXTI4,
+    5...9 => nvic::Irq::EXTI9_5,
+    _ => nvic::Irq::EXTI15_10,
+  }
+}
+
+/// Call `handler` (from the interrupt handler, with the pin's number) whenever
+/// the pin sees the given edge
+///
+/// There's only one line per pin number, so eg. PA3 and PB3 can't be used at
+/// the same time.
+pub fn listen(port: gpio::Gpio, pin: u8, edge: Edge, handler: fn(u8)) {
+  let bit = 1 << pin;
+
+  // Don't let it fire halfway through
+  mmio::unset_bits(EXTI_IMR, bit);
+
+  afio::set_exti_source(pin, port);
+
+  match edge {
+    Edge::Rising => {
+      mmio::set_bits(EXTI_RTSR, bit);
+      mmio::unset_bits(EXTI_FTSR, bit);
+    },
+    Edge::Falling => {
+      mmio::unset_bits(EXTI_RTSR, bit);
+      mmio::set_bits(EXTI_FTSR, bit);
+    },
+    Edge::Both => {
+      mmio::set_bits(EXTI_RTSR, bit);
+      mmio::set_bits(EXTI_FTSR, bit);
+    },
+  }
+
+  unsafe {
+    handlers[pin as usize] = Some(handler);
+  }
+
+  mmio::write(EXTI_PR, bit);
+  mmio::set_bits(EXTI_IMR, bit);
+
+  nvic::enable_irq(irq(pin));
+}
+
+pub fn unlisten(pin: u8) {
+  mmio::unset_bits(EXTI_IMR, 1 << pin);
+
+  unsafe {
+    handlers[pin as usize] = None;
+  }
+}
+
+/// Service the pending lines among `first`..`last` (inclusive)
+fn dispatch(first: u8, last: u8) {
+  let pending = mmio::read(EXTI_PR);
+
+  for line in first..last + 1 {
+    if pending & (1 << line) == 0 {
+      continue;
+    }
+
+    mmio::write(EXTI_PR, 1 << line);
+
+    match unsafe { handlers[line as usize] } {
+      Some(handler) => handler(line),
+      None => (),
+    }
+  }
+}
+
+pub extern "C" fn exti0_irq_handler() { dispatch(0, 0); }
+pub extern "C" fn exti1_irq_handler() { dispatch(1, 1); }
+pub extern "C" fn exti2_irq_handler() { dispatch(2, 2); }
+pub extern "C" fn exti3_irq_handler() { dispatch(3, 3); }
+pub extern "C" fn exti4_irq_handler() { dispatch(4, 4); }
+pub extern "C" fn exti9_5_irq_handler() { dispatch(5, 9); }
+pub extern "C" fn exti15_10_irq_handler() { dispatch(10, 15); }
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
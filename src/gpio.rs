@@ -6,6 +6,8 @@
 // Created on: 16 Feb 2017 21:36:10 +0100 (CET)
 //
 
+use io;
+
 #[derive(Debug, Clone, Copy)]
 pub enum PinMode {
   Analog,
@@ -35,9 +37,16 @@ struct Gpio_register_map {
   LCK:  u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Gpio(u32);
 
+/// A single pin of one of the ports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pin {
+  port: Gpio,
+  pin: u8,
+}
+
 pub const GPIOA: Gpio = Gpio(0x4001_0800);
 pub const GPIOB: Gpio = Gpio(0x4001_0C00);
 pub const GPIOC: Gpio = Gpio(0x4001_1000);
@@ -46,7 +55,16 @@ pub const GPIOE: Gpio = Gpio(0x4001_1800);
 pub const GPIOF: Gpio = Gpio(0x4001_1C00);
 pub const GPIOG: Gpio = Gpio(0x4001_2000);
 
+/// Which pins should be pulled up whenever they're inputs (one per port, see
+/// Pin::set_pull_up)
+static mut pull_ups: [u16; 7] = [0; 7];
+
 impl Gpio {
+  /// 0 for GPIOA, 1 for GPIOB and so on
+  pub fn number(&self) -> u32 {
+    (self.0 - GPIOA.0) / 0x400
+  }
+
   pub fn enable_pin(&self, pin: u8) {
     /* FIXME sanitize 'num' (possible values: 0-15 inclusive) */
     let regmap = self.0 as *mut Gpio_register_map;
@@ -65,6 +83,26 @@ impl Gpio {
     }
   }
 
+  /// The level on the pin (for both the inputs and the outputs)
+  pub fn read_pin(&self, pin: u8) -> bool {
+    let regmap = self.0 as *mut Gpio_register_map;
+
+    unsafe { (*regmap).IDR & (1u32 << pin) != 0 }
+  }
+
+  /// Whether the pin's MODE bits are 00
+  fn is_input(&self, pin: u8) -> bool {
+    let regmap = self.0 as *mut Gpio_register_map;
+
+    unsafe {
+      if pin < 8 {
+        (*regmap).CRL & (0b11 << (4 * pin)) == 0
+      } else {
+        (*regmap).CRH & (0b11 << (4 * (pin - 8))) == 0
+      }
+    }
+  }
+
   pub fn set_pin_mode(&self, pin: u8, mode: PinMode) {
     let regmap = self.0 as *mut Gpio_register_map;
 
@@ -78,8 +116,14 @@ impl Gpio {
       PinMode::OutAltDrain => 0b1110,
     };
 
+    // Pins 0-7 are configured through CRL, pins 8-15 through CRH
     unsafe {
-      (*regmap).CRL = ((*regmap).CRL & !(0b1111 << (4 * pin))) | (bits << (4 * pin));
+      if pin < 8 {
+        (*regmap).CRL = ((*regmap).CRL & !(0b1111 << (4 * pin))) | (bits << (4 * pin));
+      } else {
+        let pin = pin - 8;
+        (*regmap).CRH = ((*regmap).CRH & !(0b1111 << (4 * pin))) | (bits << (4 * pin));
+      }
     }
   }
 
@@ -94,8 +138,107 @@ impl Gpio {
     };
 
     unsafe {
-      (*regmap).CRL = ((*regmap).CRL & !(0b11 << (4 * pin))) | (bits << (4 * pin));
+      if pin < 8 {
+        (*regmap).CRL = ((*regmap).CRL & !(0b11 << (4 * pin))) | (bits << (4 * pin));
+      } else {
+        let pin = pin - 8;
+        (*regmap).CRH = ((*regmap).CRH & !(0b11 << (4 * pin))) | (bits << (4 * pin));
+      }
+    }
+  }
+}
+
+impl Pin {
+  pub const fn new(port: Gpio, pin: u8) -> Pin {
+    Pin { port: port, pin: pin }
+  }
+}
+
+impl io::IoPin for Pin {
+  fn set(&self) -> Result<(), io::Error> {
+    self.port.enable_pin(self.pin);
+    Ok(())
+  }
+
+  fn clear(&self) -> Result<(), io::Error> {
+    self.port.disable_pin(self.pin);
+    Ok(())
+  }
+
+  fn toggle(&self) -> Result<(), io::Error> {
+    let regmap = self.port.0 as *mut Gpio_register_map;
+
+    if unsafe { (*regmap).ODR & (1u32 << self.pin) != 0 } {
+      self.clear()
+    } else {
+      self.set()
+    }
+  }
+
+  fn read(&self) -> Result<bool, io::Error> {
+    Ok(self.port.read_pin(self.pin))
+  }
+
+  /// Mind that the pull-up is done through ODR, so an input with the pull-up
+  /// enabled starts off driving high once it's made an output
+  fn set_direction(&self, direction: io::Direction) -> Result<(), io::Error> {
+    match direction {
+      io::Direction::Input => self.apply_pull_up(),
+      io::Direction::Output => self.port.set_pin_mode(self.pin, PinMode::OutPP),
+    }
+
+    Ok(())
+  }
+
+  /// The setting is kept for when the pin is an output, and applied once
+  /// it's made an input again (as with the MCP23S08's GPPU)
+  fn set_pull_up(&self, enabled: bool) -> Result<(), io::Error> {
+    let port = self.port.number() as usize;
+
+    unsafe {
+      if enabled {
+        pull_ups[port] |= 1 << self.pin;
+      } else {
+        pull_ups[port] &= !(1 << self.pin);
+      }
+    }
+
+    if self.port.is_input(self.pin) {
+      self.apply_pull_up();
     }
+
+    Ok(())
+  }
+}
+
+impl Pin {
+  /// Make the pin an input, pulled up if that's what was asked for
+  fn apply_pull_up(&self) {
+    if unsafe { pull_ups[self.port.number() as usize] } & 1 << self.pin != 0 {
+      self.port.enable_pin(self.pin);
+      self.port.set_pin_mode(self.pin, PinMode::InPP);
+    } else {
+      self.port.set_pin_mode(self.pin, PinMode::InFloat);
+    }
+  }
+}
+
+impl io::IoPort for Gpio {
+  fn write(&self, value: u16) -> Result<(), io::Error> {
+    let regmap = self.0 as *mut Gpio_register_map;
+
+    // Set the ones and reset the zeros in one go
+    unsafe {
+      (*regmap).BSRR = value as u32 | (!value as u32) << 16;
+    }
+
+    Ok(())
+  }
+
+  fn read(&self) -> Result<u16, io::Error> {
+    let regmap = self.0 as *mut Gpio_register_map;
+
+    Ok(unsafe { (*regmap).IDR as u16 })
   }
 }
 
@@ -0,0 +1,467 @@
+//
+// i2c.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:24:08 +0000 (UTC)
+//
+
+use gpio;
+use rcc;
+use nvic;
+use systick;
+
+#[repr(packed)]
+struct I2C_register_map {
+  CR1: u32,
+  CR2: u32,
+  OAR1: u32,
+  OAR2: u32,
+  DR: u32,
+  SR1: u32,
+  SR2: u32,
+  CCR: u32,
+  TRISE: u32,
+}
+
+/// Software reset
+const I2C_CR1_SWRST: u32 = 1 << 15;
+/// The (N)ACK applies to the next byte, not the current one
+const I2C_CR1_POS: u32 = 1 << 11;
+/// Acknowledge the received bytes
+const I2C_CR1_ACK: u32 = 1 << 10;
+/// Generate a stop condition (after the current byte)
+const I2C_CR1_STOP: u32 = 1 << 9;
+/// Generate a (repeated) start condition
+const I2C_CR1_START: u32 = 1 << 8;
+/// Peripheral enable
+const I2C_CR1_PE: u32 = 1 << 0;
+
+/// Peripheral clock frequency in MHz (2-36)
+const I2C_CR2_FREQ: u32 = 0b11_1111;
+
+/// Timeout or Tlow error
+const I2C_SR1_TIMEOUT: u32 = 1 << 14;
+/// Acknowledge failure (cleared by writing 0)
+const I2C_SR1_AF: u32 = 1 << 10;
+/// Arbitration lost (cleared by writing 0)
+const I2C_SR1_ARLO: u32 = 1 << 9;
+/// Misplaced start or stop condition (cleared by writing 0)
+const I2C_SR1_BERR: u32 = 1 << 8;
+/// Data register empty (when transmitting)
+const I2C_SR1_TXE: u32 = 1 << 7;
+/// Data register not empty (when receiving)
+const I2C_SR1_RXNE: u32 = 1 << 6;
+/// Byte transfer finished
+const I2C_SR1_BTF: u32 = 1 << 2;
+/// Address sent and acknowledged (cleared by reading SR1 and then SR2)
+const I2C_SR1_ADDR: u32 = 1 << 1;
+/// Start condition generated
+const I2C_SR1_SB: u32 = 1 << 0;
+
+/// Communication ongoing on the bus
+const I2C_SR2_BUSY: u32 = 1 << 1;
+
+/// Fast mode
+const I2C_CCR_FS: u32 = 1 << 15;
+
+/// How long to wait for any single step of a transaction
+const TIMEOUT_MS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+  /// 100kHz
+  Standard,
+  /// 400kHz
+  Fast,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+  /// Nobody acknowledged the address or a byte
+  Nack,
+  /// Another master took over the bus
+  ArbitrationLost,
+  /// A misplaced start or stop condition
+  Bus,
+  /// The bus is stuck (eg. a slave is holding SDA or SCL low)
+  Timeout,
+}
+
+/// Base address of the registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct I2C {
+  regmap: *mut I2C_register_map,
+}
+
+pub const I2C1: I2C = I2C { regmap: 0x4000_5400 as *mut I2C_register_map };
+pub const I2C2: I2C = I2C { regmap: 0x4000_5800 as *mut I2C_register_map };
+
+impl I2C {
+  /// Enable the clock, set up the pins (I2C1: SCL on PB6, SDA on PB7, I2C2:
+  /// SCL on PB10, SDA on PB11) and configure the I2C as a master, returns the
+  /// actual SCL frequency
+  ///
+  /// The bus needs external pull-ups.
+  pub fn initialize(self, speed: Speed) -> u32 {
+    let (scl, sda) = if self == I2C1 {
+      rcc::enable(rcc::Periph::apb1_i2c1);
+      (6, 7)
+    } else {
+      rcc::enable(rcc::Periph::apb1_i2c2);
+      (10, 11)
+    };
+
+    for &pin in &[scl, sda] {
+      gpio::GPIOB.set_pin_mode(pin, gpio::PinMode::OutAltDrain);
+      gpio::GPIOB.set_pin_speed(pin, gpio::PinSpeed::Max50MHz);
+    }
+
+    let pclk1 = rcc::get_clock_speed(rcc::Clock::PCLK1);
+    let mhz = pclk1 / 1_000_000;
+    let (ccr, trise, frequency) = I2C::timing(pclk1, speed);
+
+    unsafe {
+      // Get rid of whatever state it was left in
+      (*self.regmap).CR1 = I2C_CR1_SWRST;
+      (*self.regmap).CR1 = 0;
+
+      (*self.regmap).CR2 = mhz & I2C_CR2_FREQ;
+      (*self.regmap).CCR = ccr;
+      (*self.regmap).TRISE = trise;
+
+      (*self.regmap).CR1 = I2C_CR1_PE;
+    }
+
+    debug!("I2C at {:p} configured, CCR = 0x{:04x}, SCL = {} Hz", self.regmap, ccr, frequency);
+
+    frequency
+  }
+
+  /// CCR and TRISE for the speed + the actual SCL frequency
+  fn timing(pclk1: u32, speed: Speed) -> (u32, u32, u32) {
+    let mhz = pclk1 / 1_000_000;
+
+    match speed {
+      // Thigh = Tlow = CCR * Tpclk1, rise time up to 1000ns
+      Speed::Standard => {
+        let mut ccr = pclk1 / (2 * 100_000);
+        if ccr < 4 {
+          ccr = 4;
+        }
+
+        (ccr, mhz + 1, pclk1 / (2 * ccr))
+      },
+      // Thigh = CCR * Tpclk1, Tlow = 2 * CCR * Tpclk1, rise time up to 300ns
+      Speed::Fast => {
+        let mut ccr = (pclk1 + 3 * 400_000 - 1) / (3 * 400_000);
+        if ccr < 1 {
+          ccr = 1;
+        }
+
+        (I2C_CCR_FS | ccr, mhz * 300 / 1000 + 1, pclk1 / (3 * ccr))
+      },
+    }
+  }
+
+  pub fn is_initialized(&self) -> bool {
+    unsafe { (*self.regmap).CR1 & I2C_CR1_PE != 0 }
+  }
+
+  /// Whether the I2C is enabled and the pin is its SCL or SDA
+  pub fn owns_pin(&self, port: gpio::Gpio, pin: u8) -> bool {
+    let scl = if *self == I2C1 { 6 } else { 10 };
+
+    self.is_initialized() && port == gpio::GPIOB && (pin == scl || pin == scl + 1)
+  }
+
+  fn stop(&self) {
+    unsafe {
+      (*self.regmap).CR1 |= I2C_CR1_STOP;
+    }
+  }
+
+  /// Wait for the flag in SR1, giving up on any of the errors
+  fn wait(&self, flag: u32) -> Result<(), Error> {
+    let start = systick::millis();
+
+    loop {
+      let sr1 = unsafe { (*self.regmap).SR1 };
+
+      if sr1 & I2C_SR1_AF != 0 {
+        unsafe {
+          (*self.regmap).SR1 &= !I2C_SR1_AF;
+        }
+        self.stop();
+        return Err(Error::Nack);
+      }
+
+      // The hardware lets go of the bus by itself
+      if sr1 & I2C_SR1_ARLO != 0 {
+        unsafe {
+          (*self.regmap).SR1 &= !I2C_SR1_ARLO;
+        }
+        return Err(Error::ArbitrationLost);
+      }
+
+      if sr1 & I2C_SR1_BERR != 0 {
+        unsafe {
+          (*self.regmap).SR1 &= !I2C_SR1_BERR;
+        }
+        self.stop();
+        return Err(Error::Bus);
+      }
+
+      if sr1 & flag != 0 {
+        return Ok(());
+      }
+
+      if sr1 & I2C_SR1_TIMEOUT != 0 || systick::millis().wrapping_sub(start) > TIMEOUT_MS {
+        unsafe {
+          (*self.regmap).SR1 &= !I2C_SR1_TIMEOUT;
+        }
+        self.stop();
+        return Err(Error::Timeout);
+      }
+    }
+  }
+
+  /// Wait until the previous transaction's stop condition went out
+  fn wait_stop(&self) -> Result<(), Error> {
+    let start = systick::millis();
+
+    while unsafe { (*self.regmap).CR1 & I2C_CR1_STOP != 0 } {
+      if systick::millis().wrapping_sub(start) > TIMEOUT_MS {
+        return Err(Error::Timeout);
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Send a (repeated) start condition and the address, the ADDR flag is
+  /// left for the caller to clear (which the reads need to time right)
+  fn start(&self, address: u8, read: bool) -> Result<(), Error> {
+    self.wait_stop()?;
+
+    unsafe {
+      // A repeated start doesn't have to wait for the bus to be free
+      if (*self.regmap).SR2 & I2C_SR2_BUSY != 0 && (*self.regmap).SR1 & I2C_SR1_BTF == 0 {
+        let start = systick::millis();
+
+        while (*self.regmap).SR2 & I2C_SR2_BUSY != 0 {
+          if systick::millis().wrapping_sub(start) > TIMEOUT_MS {
+            return Err(Error::Timeout);
+          }
+        }
+      }
+
+      (*self.regmap).CR1 |= I2C_CR1_START;
+    }
+
+    self.wait(I2C_SR1_SB)?;
+
+    unsafe {
+      (*self.regmap).DR = (address as u32) << 1 | read as u32;
+    }
+
+    self.wait(I2C_SR1_ADDR)
+  }
+
+  fn clear_addr(&self) {
+    unsafe {
+      let _ = (*self.regmap).SR1;
+      let _ = (*self.regmap).SR2;
+    }
+  }
+
+  /// Address the slave and send it the bytes, with or without the stop
+  /// condition at the end
+  fn write_bytes(&self, address: u8, data: &[u8], stop: bool) -> Result<(), Error> {
+    self.start(address, false)?;
+    self.clear_addr();
+
+    for &byte in data {
+      self.wait(I2C_SR1_TXE)?;
+
+      unsafe {
+        (*self.regmap).DR = byte as u32;
+      }
+    }
+
+    // The last byte has to be acknowledged as well (there's none when only
+    // probing the address)
+    if !data.is_empty() {
+      self.wait(I2C_SR1_BTF)?;
+    }
+
+    if stop {
+      self.stop();
+    }
+
+    Ok(())
+  }
+
+  /// Address the slave and read the bytes from it, followed by the stop
+  /// condition
+  ///
+  /// The last byte has to be NACKed and the stop condition has to be set at
+  /// exactly the right moment, which is why the 1-, 2- and N-byte reads are
+  /// done differently (and with the interrupts disabled at the critical
+  /// points), as per the F1 errata sheet.
+  fn read_bytes(&self, address: u8, buf: &mut [u8]) -> Result<(), Error> {
+    let len = buf.len();
+
+    unsafe {
+      if len == 2 {
+        (*self.regmap).CR1 |= I2C_CR1_ACK | I2C_CR1_POS;
+      } else if len > 2 {
+        (*self.regmap).CR1 |= I2C_CR1_ACK;
+      } else {
+        (*self.regmap).CR1 &= !I2C_CR1_ACK;
+      }
+    }
+
+    let result = self.start(address, true).and_then(|()| {
+      self.read_after_addr(buf)
+    });
+
+    unsafe {
+      (*self.regmap).CR1 &= !(I2C_CR1_ACK | I2C_CR1_POS);
+    }
+
+    result
+  }
+
+  fn read_after_addr(&self, buf: &mut [u8]) -> Result<(), Error> {
+    let len = buf.len();
+    let regmap = self.regmap;
+
+    if len == 1 {
+      nvic::without_interrupts(|| {
+        self.clear_addr();
+        self.stop();
+      });
+
+      self.wait(I2C_SR1_RXNE)?;
+      buf[0] = unsafe { (*regmap).DR as u8 };
+
+      return Ok(());
+    }
+
+    if len == 2 {
+      nvic::without_interrupts(|| unsafe {
+        self.clear_addr();
+        (*regmap).CR1 &= !I2C_CR1_ACK;
+      });
+
+      // Both bytes are in (one in DR, one in the shift register)
+      self.wait(I2C_SR1_BTF)?;
+
+      nvic::without_interrupts(|| unsafe {
+        self.stop();
+        buf[0] = (*regmap).DR as u8;
+      });
+
+      buf[1] = unsafe { (*regmap).DR as u8 };
+
+      return Ok(());
+    }
+
+    self.clear_addr();
+
+    for i in 0..len - 3 {
+      self.wait(I2C_SR1_RXNE)?;
+      buf[i] = unsafe { (*regmap).DR as u8 };
+    }
+
+    // N-2 is in DR and N-1 in the shift register, N is yet to come
+    self.wait(I2C_SR1_BTF)?;
+
+    unsafe {
+      (*regmap).CR1 &= !I2C_CR1_ACK;
+    }
+
+    nvic::without_interrupts(|| unsafe {
+      buf[len - 3] = (*regmap).DR as u8;
+      self.stop();
+      buf[len - 2] = (*regmap).DR as u8;
+    });
+
+    self.wait(I2C_SR1_RXNE)?;
+    buf[len - 1] = unsafe { (*regmap).DR as u8 };
+
+    Ok(())
+  }
+
+  /// Send the bytes to the slave (at the 7-bit address)
+  pub fn write(&self, address: u8, data: &[u8]) -> Result<(), Error> {
+    self.write_bytes(address, data, true)
+  }
+
+  /// Fill the buffer with what the slave (at the 7-bit address) sends
+  pub fn read(&self, address: u8, buf: &mut [u8]) -> Result<(), Error> {
+    if buf.is_empty() {
+      return Ok(());
+    }
+
+    self.read_bytes(address, buf)
+  }
+
+  /// Send the bytes (usually a register number) and read the response after
+  /// a repeated start, without letting go of the bus in between
+  pub fn write_read(&self, address: u8, data: &[u8], buf: &mut [u8]) -> Result<(), Error> {
+    if buf.is_empty() {
+      return self.write(address, data);
+    }
+
+    // Nothing to write, there'd be no byte to wait for BTF on
+    if data.is_empty() {
+      return self.read_bytes(address, buf);
+    }
+
+    self.write_bytes(address, data, false)?;
+    self.read_bytes(address, buf)
+  }
+
+  /// Whether anything acknowledges the address
+  pub fn probe(&self, address: u8) -> Result<bool, Error> {
+    match self.write(address, &[]) {
+      Ok(()) => Ok(true),
+      Err(Error::Nack) => Ok(false),
+      Err(err) => Err(err),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn standard_mode_timing() {
+    // PCLK1 = 36MHz (the usual one): 180 * 27.8ns high and low, TRISE is
+    // 1000ns in PCLK1 periods + 1
+    assert_eq!(I2C::timing(36_000_000, Speed::Standard), (180, 37, 100_000));
+    assert_eq!(I2C::timing(8_000_000, Speed::Standard), (40, 9, 100_000));
+  }
+
+  #[test]
+  fn fast_mode_timing() {
+    // Rounded up, so it never goes above 400kHz
+    assert_eq!(I2C::timing(36_000_000, Speed::Fast), (I2C_CCR_FS | 30, 11, 400_000));
+    assert_eq!(I2C::timing(8_000_000, Speed::Fast), (I2C_CCR_FS | 7, 3, 380_952));
+  }
+
+  #[test]
+  fn ccr_minimums() {
+    // Standard mode can't go below 4
+    assert_eq!(I2C::timing(2_000_000, Speed::Standard), (10, 3, 100_000));
+    assert_eq!(I2C::timing(500_000, Speed::Standard).0, 4);
+    assert_eq!(I2C::timing(1_000_000, Speed::Fast).0, I2C_CCR_FS | 1);
+  }
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
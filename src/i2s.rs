@@ -0,0 +1,344 @@
+//
+// i2s.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:18:13 +0000 (UTC)
+//
+
+// I2S on SPI2
+//
+// Mind that only the high-density F103s (xC, xD, xE) have it, on the others
+// (like the F103RB on the Nucleo) I2SCFGR and I2SPR are reserved and writing
+// them does nothing, so it's only built with the high_density feature. The
+// SPI2 can't be used as an SPI at the same time.
+
+use core::slice;
+
+use gpio;
+use rcc;
+use dma;
+
+#[repr(packed)]
+struct I2S_register_map {
+  CR1: u32,
+  CR2: u32,
+  SR: u32,
+  DR: u32,
+  CRCPR: u32,
+  RXCRCR: u32,
+  TXCRCR: u32,
+  I2SCFGR: u32,
+  I2SPR: u32,
+}
+
+/// The registers are shared with SPI2
+const I2S2: *mut I2S_register_map = 0x4000_3800 as *mut I2S_register_map;
+
+/// Offset of DR in the register map (for the DMA)
+const I2S_DR_OFFSET: u32 = 0x0c;
+
+/// RX buffer DMA enable
+const SPI_CR2_RXDMAEN: u32 = 1 << 0;
+/// TX buffer DMA enable
+const SPI_CR2_TXDMAEN: u32 = 1 << 1;
+
+/// I2S mode selection (as opposed to SPI)
+const I2SCFGR_I2SMOD: u32 = 1 << 11;
+/// I2S enable
+const I2SCFGR_I2SE: u32 = 1 << 10;
+/// I2S configuration mode
+///  00: slave transmit
+///  01: slave receive
+///  10: master transmit
+///  11: master receive
+const I2SCFGR_I2SCFG_SHIFT: u32 = 8;
+/// PCM frame synchronization
+///  0: short frame
+///  1: long frame
+const I2SCFGR_PCMSYNC: u32 = 1 << 7;
+/// I2S standard selection
+///  00: Philips
+///  01: MSB justified
+///  10: LSB justified
+///  11: PCM
+const I2SCFGR_I2SSTD_SHIFT: u32 = 4;
+/// Data length
+///  00: 16 bits
+///  01: 24 bits
+///  10: 32 bits
+const I2SCFGR_DATLEN_SHIFT: u32 = 1;
+/// Channel length
+///  0: 16 bits
+///  1: 32 bits
+const I2SCFGR_CHLEN: u32 = 1 << 0;
+
+/// Master clock output enable
+const I2SPR_MCKOE: u32 = 1 << 9;
+/// Odd factor for the prescaler (the divider is 2 * I2SDIV + ODD)
+const I2SPR_ODD: u32 = 1 << 8;
+
+/// SPI2 RX and TX
+const DMA_RX: dma::Channel = dma::CHANNEL4;
+const DMA_TX: dma::Channel = dma::CHANNEL5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+  MasterTransmit,
+  MasterReceive,
+  SlaveTransmit,
+  SlaveReceive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Standard {
+  Philips,
+  MsbJustified,
+  LsbJustified,
+  /// PCM with a one-bit frame sync pulse
+  PcmShort,
+  /// PCM with a 13-bit frame sync pulse
+  PcmLong,
+}
+
+/// How many bits of each sample are valid
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataLength {
+  /// In 16-bit channels, one half-word per sample
+  Bits16,
+  /// In 32-bit channels (zero-padded), still one half-word per sample
+  Bits16Extended,
+  /// In 32-bit channels, two half-words per sample (MSB half first)
+  Bits24,
+  /// Two half-words per sample (MSB half first)
+  Bits32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct I2sConfig {
+  pub role: Role,
+  pub standard: Standard,
+  pub data_length: DataLength,
+  /// In Hz, only used by the master (the actual one is the closest one that
+  /// the prescaler can give)
+  pub sample_rate: u32,
+  /// Output the master clock (256 times the sample rate) on PC6
+  pub master_clock: bool,
+}
+
+/// The buffer and the callback of the stream in progress
+static mut stream: Option<(*mut u16, usize, fn(&mut [u16]))> = None;
+
+fn is_transmitter(role: Role) -> bool {
+  role == Role::MasterTransmit || role == Role::SlaveTransmit
+}
+
+/// Enable the clock, set up the pins (WS on PB12, CK on PB13, SD on PB15 and
+/// MCK on PC6) and configure the I2S, returns the actual sample rate (or 0
+/// when in slave mode)
+pub fn initialize(config: &I2sConfig) -> u32 {
+  rcc::enable(rcc::Periph::apb1_spi2);
+
+  let master = config.role == Role::MasterTransmit || config.role == Role::MasterReceive;
+
+  // WS, CK
+  for &pin in &[12, 13] {
+    if master {
+      gpio::GPIOB.set_pin_mode(pin, gpio::PinMode::OutAltPP);
+      gpio::GPIOB.set_pin_speed(pin, gpio::PinSpeed::Max50MHz);
+    } else {
+      gpio::GPIOB.set_pin_mode(pin, gpio::PinMode::InFloat);
+    }
+  }
+
+  // SD
+  if is_transmitter(config.role) {
+    gpio::GPIOB.set_pin_mode(15, gpio::PinMode::OutAltPP);
+    gpio::GPIOB.set_pin_speed(15, gpio::PinSpeed::Max50MHz);
+  } else {
+    gpio::GPIOB.set_pin_mode(15, gpio::PinMode::InFloat);
+  }
+
+  // MCK
+  if master && config.master_clock {
+    gpio::GPIOC.set_pin_mode(6, gpio::PinMode::OutAltPP);
+    gpio::GPIOC.set_pin_speed(6, gpio::PinSpeed::Max50MHz);
+  }
+
+  let mut i2scfgr = I2SCFGR_I2SMOD;
+
+  i2scfgr |= match config.role {
+    Role::SlaveTransmit => 0b00,
+    Role::SlaveReceive => 0b01,
+    Role::MasterTransmit => 0b10,
+    Role::MasterReceive => 0b11,
+  } << I2SCFGR_I2SCFG_SHIFT;
+
+  i2scfgr |= match config.standard {
+    Standard::Philips => 0b00,
+    Standard::MsbJustified => 0b01,
+    Standard::LsbJustified => 0b10,
+    Standard::PcmShort => 0b11,
+    Standard::PcmLong => 0b11,
+  } << I2SCFGR_I2SSTD_SHIFT;
+
+  if config.standard == Standard::PcmLong {
+    i2scfgr |= I2SCFGR_PCMSYNC;
+  }
+
+  i2scfgr |= match config.data_length {
+    DataLength::Bits16 => 0,
+    DataLength::Bits16Extended => I2SCFGR_CHLEN,
+    DataLength::Bits24 => 0b01 << I2SCFGR_DATLEN_SHIFT | I2SCFGR_CHLEN,
+    DataLength::Bits32 => 0b10 << I2SCFGR_DATLEN_SHIFT | I2SCFGR_CHLEN,
+  };
+
+  let (i2spr, sample_rate) = if master {
+    prescaler(config)
+  } else {
+    (0, 0)
+  };
+
+  unsafe {
+    (*I2S2).I2SCFGR &= !I2SCFGR_I2SE;
+    (*I2S2).I2SCFGR = i2scfgr;
+    (*I2S2).I2SPR = i2spr;
+  }
+
+  debug!("I2SCFGR = 0x{:04x}, I2SPR = 0x{:04x}, Fs = {} Hz", i2scfgr, i2spr, sample_rate);
+
+  sample_rate
+}
+
+/// I2SPR for the requested sample rate + the actual sample rate
+fn prescaler(config: &I2sConfig) -> (u32, u32) {
+  // The I2S2 clock is SYSCLK itself
+  let i2sclk = rcc::get_clock_speed(rcc::Clock::SYSCLK);
+
+  // How many I2S clock periods per sample: either the master clock (256 * Fs)
+  // gets divided, or the bit clock (2 channels * 16 or 32 bits)
+  let periods = if config.master_clock {
+    256
+  } else if config.data_length == DataLength::Bits16 {
+    32
+  } else {
+    64
+  };
+
+  let wanted = config.sample_rate * periods;
+
+  // The divider is 2 * I2SDIV + ODD, with I2SDIV between 2 and 255 (rounded
+  // to the nearest one)
+  let mut divider = (i2sclk + wanted / 2) / wanted;
+  if divider < 4 {
+    divider = 4;
+  } else if divider > 511 {
+    divider = 511;
+  }
+
+  let mut i2spr = divider / 2;
+
+  if divider & 1 != 0 {
+    i2spr |= I2SPR_ODD;
+  }
+
+  if config.master_clock {
+    i2spr |= I2SPR_MCKOE;
+  }
+
+  (i2spr, i2sclk / (periods * divider))
+}
+
+/// Start streaming the buffer over and over, with the DMA
+///
+/// The buffer is used as two halves: while the DMA goes through one of them,
+/// `callback` gets the other one (from the interrupt handler) to fill it with
+/// the next samples (when transmitting) or to take the samples out of it
+/// (when receiving). When transmitting, the whole buffer should be filled
+/// before starting.
+///
+/// Returns false if the DMA channel is taken (eg. by the SPI2) or the buffer
+/// is too long for it.
+pub fn start_stream(buf: &'static mut [u16], callback: fn(&mut [u16])) -> bool {
+  let transmit = unsafe { (*I2S2).I2SCFGR & (0b01 << I2SCFGR_I2SCFG_SHIFT) == 0 };
+  let channel = if transmit { DMA_TX } else { DMA_RX };
+
+  if buf.len() < 2 || buf.len() > 0xffff || !channel.claim() {
+    return false;
+  }
+
+  let memory = buf.as_mut_ptr();
+  let len = buf.len();
+
+  unsafe {
+    stream = Some((memory, len, callback));
+  }
+
+  channel.set_callback(Some(dma_event));
+  channel.start(&dma::Transfer {
+    peripheral: I2S2 as u32 + I2S_DR_OFFSET,
+    memory: memory as u32,
+    count: len as u16,
+    direction: if transmit { dma::Direction::MemoryToPeripheral } else { dma::Direction::PeripheralToMemory },
+    memory_increment: true,
+    size: dma::Size::Bits16,
+    circular: true,
+    priority: dma::Priority::High,
+  });
+
+  unsafe {
+    (*I2S2).CR2 |= if transmit { SPI_CR2_TXDMAEN } else { SPI_CR2_RXDMAEN };
+    (*I2S2).I2SCFGR |= I2SCFGR_I2SE;
+  }
+
+  true
+}
+
+pub fn stop_stream() {
+  let transmit = unsafe { (*I2S2).I2SCFGR & (0b01 << I2SCFGR_I2SCFG_SHIFT) == 0 };
+  let channel = if transmit { DMA_TX } else { DMA_RX };
+
+  unsafe {
+    if stream.is_none() {
+      return;
+    }
+
+    (*I2S2).I2SCFGR &= !I2SCFGR_I2SE;
+    (*I2S2).CR2 &= !(SPI_CR2_RXDMAEN | SPI_CR2_TXDMAEN);
+  }
+
+  channel.set_callback(None);
+  channel.release();
+
+  unsafe {
+    stream = None;
+  }
+}
+
+pub fn is_streaming() -> bool {
+  unsafe { stream.is_some() }
+}
+
+fn dma_event(event: dma::Event) {
+  let (memory, len, callback) = match unsafe { stream } {
+    Some(current) => current,
+    None => return,
+  };
+
+  let half = len / 2;
+
+  match event {
+    dma::Event::HalfComplete => callback(unsafe { slice::from_raw_parts_mut(memory, half) }),
+    dma::Event::Complete => callback(unsafe { slice::from_raw_parts_mut(memory.offset(half as isize), len - half) }),
+    dma::Event::Error => {
+      // The channel is disabled by the hardware already, but the I2S would
+      // keep running (and underrunning)
+      stop_stream();
+      error!("DMA transfer error, stream stopped");
+    },
+  }
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
@@ -0,0 +1,111 @@
+//
+// io.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:21:22 +0000 (UTC)
+//
+
+use spi;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+  Input,
+  Output,
+}
+
+/// What can go wrong when accessing a pin (the on-chip ones never fail)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+  /// The pin is behind an SPI bus (eg. on an expander)
+  Spi(spi::Error),
+  /// There's no such pin (eg. pin 8 of an MCP23S08)
+  InvalidPin(u8),
+}
+
+impl From<spi::Error> for Error {
+  fn from(err: spi::Error) -> Error {
+    Error::Spi(err)
+  }
+}
+
+/// A single pin, wherever it is (gpio::Pin, mcp23s08::Pin), the drivers that
+/// take a `&IoPin` work with any of them
+pub trait IoPin {
+  /// Drive the output high
+  fn set(&self) -> Result<(), Error>;
+  /// Drive the output low
+  fn clear(&self) -> Result<(), Error>;
+  fn toggle(&self) -> Result<(), Error>;
+  /// The level on the pin
+  fn read(&self) -> Result<bool, Error>;
+  fn set_direction(&self, direction: Direction) -> Result<(), Error>;
+  /// Only has any effect on the inputs, but it's remembered (and applied once
+  /// the pin becomes an input) for the outputs too
+  fn set_pull_up(&self, enabled: bool) -> Result<(), Error>;
+
+  fn write(&self, high: bool) -> Result<(), Error> {
+    if high {
+      self.set()
+    } else {
+      self.clear()
+    }
+  }
+}
+
+/// All the pins of a port at once (gpio::Gpio, mcp23s08::Mcp23s08), bit n
+/// is pin n
+pub trait IoPort {
+  /// Drive all the outputs (what's written to the inputs only matters once
+  /// they're made outputs)
+  fn write(&self, value: u16) -> Result<(), Error>;
+  /// The levels of all the pins
+  fn read(&self) -> Result<u16, Error>;
+}
+
+/// Filters out the bouncing of a switch: the level only counts once it's been
+/// the same for `samples` updates in a row
+pub struct Debouncer<'a> {
+  pin: &'a IoPin,
+  samples: u8,
+  /// How many updates in a row saw the level that's different than `state`
+  count: u8,
+  state: bool,
+}
+
+impl<'a> Debouncer<'a> {
+  pub fn new(pin: &'a IoPin, samples: u8) -> Debouncer<'a> {
+    Debouncer { pin: pin, samples: samples, count: 0, state: false }
+  }
+
+  /// Sample the pin (this should be done periodically, eg. every millisecond),
+  /// returns the new level once it changes
+  pub fn update(&mut self) -> Result<Option<bool>, Error> {
+    let level = self.pin.read()?;
+
+    if level == self.state {
+      self.count = 0;
+      return Ok(None);
+    }
+
+    self.count += 1;
+
+    if self.count < self.samples {
+      return Ok(None);
+    }
+
+    self.count = 0;
+    self.state = level;
+
+    Ok(Some(level))
+  }
+
+  /// The last stable level
+  pub fn state(&self) -> bool {
+    self.state
+  }
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
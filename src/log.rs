@@ -0,0 +1,362 @@
+//
+// log.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:09:47 +0000 (UTC)
+//
+
+use core::fmt;
+use core::ptr;
+
+use mmio;
+use nvic;
+use systick;
+use usart;
+use ringbuf::RingBuf;
+
+/// ITM stimulus port 0
+const ITM_STIM0: u32 = 0xE000_0000;
+/// ITM Trace Enable Register
+const ITM_TER: u32 = 0xE000_0E00;
+/// ITM Trace Control Register
+const ITM_TCR: u32 = 0xE000_0E80;
+/// ITM enable
+const ITM_TCR_ITMENA: u32 = 1 << 0;
+/// ITM Lock Access Register
+const ITM_LAR: u32 = 0xE000_0FB0;
+/// What has to be written to ITM_LAR to unlock the other ITM registers
+const ITM_LAR_KEY: u32 = 0xC5AC_CE55;
+/// Debug Exception and Monitor Control Register
+const DEMCR: u32 = 0xE000_EDFC;
+/// Global enable for the DWT and ITM
+const DEMCR_TRCENA: u32 = 1 << 24;
+
+/// How many per-module levels can be set at a time
+const MAX_FILTERS: usize = 8;
+/// Longest module name a filter can hold
+const MAX_MODULE_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+  Off,
+  Error,
+  Warn,
+  Info,
+  Debug,
+  Trace,
+}
+
+/// Where the messages go (the values are bits in `sinks`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sink {
+  /// Whichever port print! goes to
+  Console = 1 << 0,
+  /// A ring buffer in RAM, oldest messages get overwritten
+  Ram = 1 << 1,
+  /// ITM stimulus port 0 (through the SWO pin)
+  Itm = 1 << 2,
+}
+
+#[derive(Clone, Copy)]
+struct Filter {
+  module: [u8; MAX_MODULE_LEN],
+  len: usize,
+  level: Level,
+}
+
+/// The level for the modules without a filter of their own
+static mut global_level: Level = Level::Info;
+static mut filters: [Option<Filter>; MAX_FILTERS] = [None; MAX_FILTERS];
+static mut sinks: u32 = Sink::Console as u32;
+static mut timestamps: bool = true;
+static mut ram_buffer: RingBuf = RingBuf::new();
+/// Bytes that didn't fit in the console's transmit buffer
+static mut console_lost: u32 = 0;
+
+impl Level {
+  pub fn from_str(name: &str) -> Option<Level> {
+    match name {
+      "off" => Some(Level::Off),
+      "error" => Some(Level::Error),
+      "warn" => Some(Level::Warn),
+      "info" => Some(Level::Info),
+      "debug" => Some(Level::Debug),
+      "trace" => Some(Level::Trace),
+      _ => None,
+    }
+  }
+
+  fn tag(&self) -> &'static str {
+    match *self {
+      Level::Off => "",
+      Level::Error => "E",
+      Level::Warn => "W",
+      Level::Info => "I",
+      Level::Debug => "D",
+      Level::Trace => "T",
+    }
+  }
+}
+
+/// Drop the crate's name from the module path ("stm32_os::spi" -> "spi")
+fn short_module(path: &str) -> &str {
+  match path.find("::") {
+    Some(i) => &path[i + 2..],
+    None => path,
+  }
+}
+
+/// Does the filter apply to the module (or a parent module of it)?
+fn filter_matches(filter: &Filter, module: &str) -> bool {
+  let name = &filter.module[..filter.len];
+  let module = module.as_bytes();
+
+  module.starts_with(name) && (module.len() == name.len() || module[name.len()..].starts_with(b"::"))
+}
+
+pub fn level_for(module: &str) -> Level {
+  let module = short_module(module);
+
+  unsafe {
+    for filter in filters.iter() {
+      match *filter {
+        Some(ref filter) if filter_matches(filter, module) => return filter.level,
+        _ => (),
+      }
+    }
+
+    global_level
+  }
+}
+
+pub fn set_default_level(level: Level) {
+  unsafe {
+    global_level = level;
+  }
+}
+
+pub fn default_level() -> Level {
+  unsafe { global_level }
+}
+
+/// Set the level for a given module (without the crate's name, ie. "spi"),
+/// returns false if there's no room for another filter or the name's too long
+pub fn set_level(module: &str, level: Level) -> bool {
+  if module.len() > MAX_MODULE_LEN {
+    return false;
+  }
+
+  let mut filter = Filter { module: [0; MAX_MODULE_LEN], len: module.len(), level: level };
+  filter.module[..module.len()].copy_from_slice(module.as_bytes());
+
+  unsafe {
+    // Either replace the existing filter or take the first free slot
+    let mut free = None;
+
+    for i in 0..MAX_FILTERS {
+      let same = match filters[i] {
+        Some(ref existing) => &existing.module[..existing.len] == module.as_bytes(),
+        None => false,
+      };
+
+      if same {
+        filters[i] = Some(filter);
+        return true;
+      }
+
+      if free.is_none() && filters[i].is_none() {
+        free = Some(i);
+      }
+    }
+
+    match free {
+      Some(i) => {
+        filters[i] = Some(filter);
+        true
+      },
+      None => false,
+    }
+  }
+}
+
+/// Make the module follow the default level again
+pub fn clear_level(module: &str) {
+  unsafe {
+    for filter in filters.iter_mut() {
+      let matches = match *filter {
+        Some(ref existing) => &existing.module[..existing.len] == module.as_bytes(),
+        None => false,
+      };
+
+      if matches {
+        *filter = None;
+      }
+    }
+  }
+}
+
+/// Call `f` with every module that has its own level
+pub fn for_each_filter<F>(mut f: F) where F: FnMut(&str, Level) {
+  unsafe {
+    for filter in filters.iter() {
+      match *filter {
+        Some(ref filter) => f(::core::str::from_utf8_unchecked(&filter.module[..filter.len]), filter.level),
+        None => (),
+      }
+    }
+  }
+}
+
+pub fn enable_sink(sink: Sink) {
+  if sink == Sink::Itm {
+    mmio::set_bits(DEMCR, DEMCR_TRCENA);
+    mmio::write(ITM_LAR, ITM_LAR_KEY);
+    mmio::set_bits(ITM_TCR, ITM_TCR_ITMENA);
+    mmio::set_bits(ITM_TER, 1 << 0);
+  }
+
+  unsafe {
+    sinks |= sink as u32;
+  }
+}
+
+pub fn disable_sink(sink: Sink) {
+  unsafe {
+    sinks &= !(sink as u32);
+  }
+}
+
+pub fn is_sink_enabled(sink: Sink) -> bool {
+  unsafe { sinks & sink as u32 != 0 }
+}
+
+pub fn set_timestamps(enabled: bool) {
+  unsafe {
+    timestamps = enabled;
+  }
+}
+
+pub fn timestamps_enabled() -> bool {
+  unsafe { timestamps }
+}
+
+/// Take the oldest byte out of the RAM sink
+pub fn pop_ram() -> Option<u8> {
+  nvic::without_interrupts(|| unsafe { ram_buffer.pop() })
+}
+
+/// How many bytes of the messages logged from the interrupt handlers (or with
+/// the interrupts masked) were lost because the console couldn't take them
+pub fn console_dropped() -> u32 {
+  unsafe { ptr::read_volatile(&console_lost) }
+}
+
+/// Writes every byte to all the enabled sinks
+struct SinkWriter;
+
+impl SinkWriter {
+  fn put(&self, byte: u8) {
+    let sinks_enabled = unsafe { sinks };
+
+    if sinks_enabled & Sink::Console as u32 != 0 {
+      unsafe {
+        match usart::current {
+          // Waiting for the port from a handler would stall everything else
+          // (and with an unbuffered port, for as long as the byte takes to
+          // go out), so drop what doesn't fit right away
+          Some(usart) if nvic::in_handler() || nvic::interrupts_disabled() => {
+            if !usart.try_send_byte(byte) {
+              nvic::without_interrupts(|| console_lost += 1);
+            }
+          },
+          Some(usart) => usart.send_byte(byte),
+          None => (),
+        }
+      }
+    }
+
+    if sinks_enabled & Sink::Ram as u32 != 0 {
+      // Messages can come from the interrupt handlers too
+      nvic::without_interrupts(|| unsafe {
+        if ram_buffer.is_full() {
+          ram_buffer.pop();
+        }
+
+        ram_buffer.push(byte);
+      });
+    }
+
+    // The debugger is free to turn the port off behind our back
+    if sinks_enabled & Sink::Itm as u32 != 0 && mmio::read(ITM_TCR) & ITM_TCR_ITMENA != 0 && mmio::read(ITM_TER) & 1 != 0 {
+      // Reading 1 means the port can accept another byte
+      while mmio::read(ITM_STIM0) & 1 == 0 {}
+      mmio::write_u8(ITM_STIM0, byte);
+    }
+  }
+}
+
+impl fmt::Write for SinkWriter {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    for byte in s.bytes() {
+      self.put(byte);
+    }
+
+    Ok(())
+  }
+}
+
+/// Use the macros instead (error!, warn!, info!, debug!, trace!)
+///
+/// Messages logged from the interrupt handlers may end up interleaved with the
+/// ones that were being logged when the interrupt fired. They never wait for
+/// the console: whatever doesn't fit in its transmit buffer is dropped (see
+/// console_dropped), so it's best to have the port buffered.
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+  use core::fmt::Write;
+
+  if level == Level::Off || level > level_for(module) {
+    return;
+  }
+
+  let mut writer = SinkWriter;
+
+  if timestamps_enabled() && systick::is_running() {
+    let ms = systick::millis();
+    let _ = write!(writer, "[{:5}.{:03}] ", ms / 1000, ms % 1000);
+  }
+
+  let _ = write!(writer, "{} {}: ", level.tag(), short_module(module));
+  let _ = writer.write_fmt(args);
+  let _ = writer.write_str("\r\n");
+}
+
+macro_rules! log {
+  ($level:expr, $($arg:tt)*) => ({
+    $crate::log::log($level, module_path!(), format_args!($($arg)*));
+  });
+}
+
+macro_rules! error {
+  ($($arg:tt)*) => (log!($crate::log::Level::Error, $($arg)*));
+}
+
+macro_rules! warn {
+  ($($arg:tt)*) => (log!($crate::log::Level::Warn, $($arg)*));
+}
+
+macro_rules! info {
+  ($($arg:tt)*) => (log!($crate::log::Level::Info, $($arg)*));
+}
+
+macro_rules! debug {
+  ($($arg:tt)*) => (log!($crate::log::Level::Debug, $($arg)*));
+}
+
+macro_rules! trace {
+  ($($arg:tt)*) => (log!($crate::log::Level::Trace, $($arg)*));
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
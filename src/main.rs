@@ -1,10 +1,15 @@
 #![feature(lang_items)]
 #![feature(core_intrinsics)]
 #![feature(asm)]
-#![no_main]
-#![no_std]
+#![feature(const_fn)]
+// The unit tests are built for (and run on) the host, with std
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(not(test))]
 extern crate rlibc;
+#[cfg(test)]
+extern crate core;
 
 use core::fmt::Write;
 use core::slice;
@@ -12,6 +17,8 @@ use core::str;
 
 #[macro_use]
 mod usart;
+#[macro_use]
+mod log;
 mod rcc;
 mod gpio;
 mod mmio;
@@ -19,12 +26,29 @@ mod conf;
 mod cmd;
 mod spi;
 mod mcp23s08;
+mod nvic;
+mod afio;
+mod ringbuf;
+mod readline;
+mod systick;
+mod dma;
+mod exti;
+#[cfg(feature = "high_density")]
+mod i2s;
+mod io;
+mod i2c;
+mod adc;
+mod timer;
 
 #[export_name = "_reset"]
 pub extern "C" fn main() -> ! {
   rcc::initialize_clocks();
 
   move_data_section_to_ram();
+  zero_bss_section();
+
+  // Tick every millisecond (for the timestamps in the logs)
+  systick::initialize();
 
   rcc::enable(rcc::Periph::apb2_gpioa);
   rcc::enable(rcc::Periph::apb2_gpiob);
@@ -34,43 +58,49 @@ pub extern "C" fn main() -> ! {
   rcc::enable(rcc::Periph::apb2_gpiof);
   rcc::enable(rcc::Periph::apb2_gpiog);
   rcc::enable(rcc::Periph::apb2_afio);
-  rcc::enable(rcc::Periph::apb2_spi1);
-  rcc::enable(rcc::Periph::apb1_usart2);
+  rcc::enable(rcc::Periph::ahb_dma1);
 
   // Initialize USART2 (the one that goes through the debugger/the USB cable)
   usart::USART2.initialize(usart::Baudrate::_115200);
+  // Don't make every print! wait for the bytes to go through the wire
+  usart::USART2.enable_tx_buffering();
 
   usart::output_to(usart::USART2);
 
   // Configure SPI1
-  spi::SPI1.initialize();
+  let spi1_frequency = spi::SPI1.initialize(&spi::DEFAULT_CONFIG);
 
   print!("Clocks initialized\r\n");
   print!("SYSCLK = {} Hz\r\n", rcc::get_clock_speed(rcc::Clock::SYSCLK));
   print!("HCLK   = {} Hz\r\n", rcc::get_clock_speed(rcc::Clock::HCLK));
   print!("PCLK1  = {} Hz\r\n", rcc::get_clock_speed(rcc::Clock::PCLK1));
   print!("PCLK2  = {} Hz\r\n", rcc::get_clock_speed(rcc::Clock::PCLK2));
+  print!("ADCCLK = {} Hz\r\n", rcc::get_clock_speed(rcc::Clock::ADCCLK));
+  print!("SPI1   = {} Hz\r\n", spi1_frequency);
   print!("\r\n");
 
   print!("Using MCP23S08 through SPI1 to enable port GP0\r\n");
-  mcp23s08::write_reg(spi::SPI1, mcp23s08::IODIR, !0x01);
-  mcp23s08::write_reg(spi::SPI1, mcp23s08::OLAT, 0x01);
+  mcp23s08::DEVICE.initialize().unwrap();
+  mcp23s08::DEVICE.set_direction(0, mcp23s08::Direction::Output).unwrap();
+  mcp23s08::DEVICE.set_output(0, true).unwrap();
 
   print!("Available command is 'gpio <set|clear> <port> <pin>'\r\n");
 
+  let mut editor = readline::Editor::new();
+
   loop {
-    let mut buf = [0u8; 32];
-    print!(": ");
+    let mut buf = [0u8; readline::LINE_SIZE];
 
-    usart::USART2.get_string(&mut buf);
+    let len = editor.read_line(Console(usart::USART2), ": ", &mut buf);
 
     let input = unsafe {
-      str::from_utf8_unchecked(slice::from_raw_parts(buf.as_ptr(), buf.len()))
+      str::from_utf8_unchecked(slice::from_raw_parts(buf.as_ptr(), len))
     };
 
-    let mut args = input.split('\u{0}').next().unwrap().split(' ');
+    let mut args = input.split(' ');
 
     match args.next() {
+      Some("") => (),
       Some(command) => {
         match cmd::lookup_command(command) {
           Some(handler) => {
@@ -86,8 +116,35 @@ pub extern "C" fn main() -> ! {
   }
 }
 
+/// The port the commands come from, the work that the interrupt handlers
+/// leave for later gets done while waiting for them
+#[derive(Clone, Copy)]
+struct Console(usart::Usart);
+
+impl readline::Terminal for Console {
+  fn get_byte(&self) -> u8 {
+    while !self.0.has_byte() {
+      mcp23s08::service_pending();
+    }
+
+    self.0.get_byte()
+  }
+
+  fn send_byte(&self, byte: u8) {
+    self.0.send_byte(byte);
+  }
+}
+
 mod exception {
+  use usart;
+  use systick;
+  use dma;
+  use exti;
+  use spi;
+  use timer;
+
   pub extern "C" fn dummy_handler() {
+    #[cfg(target_arch = "arm")]
     unsafe { asm!("bkpt"); }
     loop {}
   }
@@ -107,7 +164,55 @@ mod exception {
     None, // Reserved for debug
     None, // Reserved
     Some(dummy_handler), // PendSV
-    Some(dummy_handler), // Systick
+    Some(systick::systick_handler), // Systick
+  ];
+
+  /// Peripheral interrupts (medium-density devices have 43 of them)
+  #[export_name = "_INTERRUPTS"]
+  pub static INTERRUPTS: [Option<extern "C" fn()>; 43] = [
+    Some(dummy_handler), // WWDG
+    Some(dummy_handler), // PVD
+    Some(dummy_handler), // TAMPER
+    Some(dummy_handler), // RTC
+    Some(dummy_handler), // FLASH
+    Some(dummy_handler), // RCC
+    Some(exti::exti0_irq_handler), // EXTI0
+    Some(exti::exti1_irq_handler), // EXTI1
+    Some(exti::exti2_irq_handler), // EXTI2
+    Some(exti::exti3_irq_handler), // EXTI3
+    Some(exti::exti4_irq_handler), // EXTI4
+    Some(dma::dma1_channel1_irq_handler), // DMA1 channel 1
+    Some(dma::dma1_channel2_irq_handler), // DMA1 channel 2
+    Some(dma::dma1_channel3_irq_handler), // DMA1 channel 3
+    Some(dma::dma1_channel4_irq_handler), // DMA1 channel 4
+    Some(dma::dma1_channel5_irq_handler), // DMA1 channel 5
+    Some(dma::dma1_channel6_irq_handler), // DMA1 channel 6
+    Some(dma::dma1_channel7_irq_handler), // DMA1 channel 7
+    Some(dummy_handler), // ADC1 and ADC2
+    Some(dummy_handler), // USB high priority or CAN TX
+    Some(dummy_handler), // USB low priority or CAN RX0
+    Some(dummy_handler), // CAN RX1
+    Some(dummy_handler), // CAN SCE
+    Some(exti::exti9_5_irq_handler), // EXTI lines 9:5
+    Some(dummy_handler), // TIM1 break
+    Some(timer::tim1_up_irq_handler), // TIM1 update
+    Some(dummy_handler), // TIM1 trigger and commutation
+    Some(timer::tim1_cc_irq_handler), // TIM1 capture compare
+    Some(timer::tim2_irq_handler), // TIM2
+    Some(timer::tim3_irq_handler), // TIM3
+    Some(timer::tim4_irq_handler), // TIM4
+    Some(dummy_handler), // I2C1 event
+    Some(dummy_handler), // I2C1 error
+    Some(dummy_handler), // I2C2 event
+    Some(dummy_handler), // I2C2 error
+    Some(spi::spi1_irq_handler), // SPI1
+    Some(spi::spi2_irq_handler), // SPI2
+    Some(usart::usart1_irq_handler), // USART1
+    Some(usart::usart2_irq_handler), // USART2
+    Some(usart::usart3_irq_handler), // USART3
+    Some(exti::exti15_10_irq_handler), // EXTI lines 15:10
+    Some(dummy_handler), // RTC alarm through EXTI
+    Some(dummy_handler), // USB wakeup from suspend through EXTI
   ];
 }
 
@@ -119,14 +224,29 @@ fn move_data_section_to_ram() {
     static data_in_ram_end: u32;
   }
 
-  let from = &data_in_flash_start as *const u32;
-  let mut to = &data_in_ram_start as *const u32;
-  let mut size = &data_in_ram_end as *const u32 as u32 - &data_in_ram_start as *const u32 as u32;
+  let from = &data_in_flash_start as *const u32 as u32;
+  let to = &data_in_ram_start as *const u32 as u32;
+  let size = &data_in_ram_end as *const u32 as u32 - to;
+
+  for i in 0..size {
+    unsafe {
+      core::ptr::write((to + i) as *mut u8, *((from + i) as *const u8));
+    }
+  }
+}
+
+fn zero_bss_section() {
+  extern {
+    static bss_start: u32;
+    static bss_end: u32;
+  }
+
+  let start = &bss_start as *const u32 as u32;
+  let size = &bss_end as *const u32 as u32 - start;
 
   for i in 0..size {
     unsafe {
-      core::ptr::write((&mut to as *mut _ as u32 + i) as *mut u8,
-              *((from as u32 + i) as *const u8));
+      core::ptr::write((start + i) as *mut u8, 0);
     }
   }
 }
@@ -143,6 +263,7 @@ pub unsafe extern fn __aeabi_memclr4(s: *mut u8, n: usize) -> *mut u8 {
   return s;
 }
 
+#[cfg(not(test))]
 mod lang_items {
   #[lang = "panic_fmt"]
   #[no_mangle]
@@ -8,7 +8,14 @@
 
 use spi;
 use gpio;
+use exti;
+use nvic;
+use io;
 
+pub use io::Direction;
+
+// The registers, in the MCP23S08 order (for the 16-bit parts these are the
+// registers of port A with BANK=1, see Mcp23s08::reg_address)
 pub const IODIR:   u8 = 0x00;
 pub const IPOL:    u8 = 0x01;
 pub const GPINTEN: u8 = 0x02;
@@ -21,12 +28,567 @@ pub const INTCAP:  u8 = 0x08;
 pub const GPIO:    u8 = 0x09;
 pub const OLAT:    u8 = 0x0a;
 
-pub fn write_reg(spi: spi::SPI, reg: u8, value: u8) {
-  gpio::GPIOC.disable_pin(0);
-  spi.send_recv_byte(0x40);
-  spi.send_recv_byte(reg);
-  spi.send_recv_byte(value);
-  gpio::GPIOC.enable_pin(0);
+/// Registers of both ports are kept apart (0) or interleaved (1), only on the
+/// 16-bit parts
+pub const IOCON_BANK: u8 = 1 << 7;
+/// INTA and INTB are internally connected, only on the 16-bit parts
+pub const IOCON_MIRROR: u8 = 1 << 6;
+/// Sequential operation disable (the address pointer doesn't increment)
+pub const IOCON_SEQOP: u8 = 1 << 5;
+/// Hardware address enable (the address pins are ignored unless it's set)
+pub const IOCON_HAEN: u8 = 1 << 3;
+/// INT is an open-drain output
+pub const IOCON_ODR: u8 = 1 << 2;
+/// INT polarity (active high when set)
+pub const IOCON_INTPOL: u8 = 1 << 1;
+
+/// The fixed part of the opcode (followed by the address, A2:A0, and the R/W
+/// bit)
+const OPCODE: u8 = 0x40;
+/// R/W bit of the opcode
+const OPCODE_READ: u8 = 0x01;
+
+/// IOCON of the 16-bit parts with BANK=0
+const IOCON_BANK0: u8 = 0x0a;
+/// IOCON of the 16-bit parts with BANK=1 (GPINTENB with BANK=0)
+const IOCON_BANK1: u8 = 0x05;
+
+/// All the chips on the board share one CS, on SPI1 (they can go up to 10MHz
+/// and work in modes 0 and 3)
+pub const SPI_DEVICE: spi::SpiDevice = spi::SpiDevice::new(spi::SPI1, spi::ChipSelect::Pin(gpio::GPIOC, 0), spi::SpiConfig {
+  mode: spi::Mode::Mode0,
+  frequency: 10_000_000,
+  frame_size: spi::FrameSize::Bits8,
+  bit_order: spi::BitOrder::MsbFirst,
+  crc: None,
+});
+
+/// The chip on the board (with A1:A0 tied to ground)
+pub const DEVICE: Mcp23s08 = Mcp23s08::new(SPI_DEVICE, 0);
+
+/// How many chips can have their INT output connected at a time
+const MAX_INTERRUPT_SOURCES: usize = 4;
+
+/// The chips of the family this driver handles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variant {
+  /// 8 pins, A1:A0
+  Mcp23s08,
+  /// 16 pins (two ports), A2:A0
+  Mcp23s17,
+  /// 16 pins (two ports) with open-drain outputs, no address pins
+  Mcp23s18,
+}
+
+/// How the registers of the 16-bit parts are laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bank {
+  /// BANK=0, each register of port A is followed by its port B counterpart
+  /// (so both of them can be accessed in one go)
+  Interleaved,
+  /// BANK=1, port A registers at 0x00-0x0a, port B ones at 0x10-0x1a
+  Separate,
+}
+
+/// What makes a pin raise the interrupt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compare {
+  /// Any change of the level
+  Previous,
+  /// The level being different than the given one (through DEFVAL)
+  Default(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+  Rising,
+  Falling,
+}
+
+/// What can go wrong when talking to the chips
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+  Spi(spi::Error),
+  /// The chip doesn't have a pin with that number
+  InvalidPin(u8),
+}
+
+impl From<spi::Error> for Error {
+  fn from(err: spi::Error) -> Error {
+    Error::Spi(err)
+  }
+}
+
+impl From<Error> for io::Error {
+  fn from(err: Error) -> io::Error {
+    match err {
+      Error::Spi(err) => io::Error::Spi(err),
+      Error::InvalidPin(pin) => io::Error::InvalidPin(pin),
+    }
+  }
+}
+
+/// A pin that raised the interrupt, along with the level it had at that time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+  /// What the chip's address pins are strapped to (A1:A0 on the MCP23S08,
+  /// A2:A0 on the MCP23S17, always 0 on the MCP23S18)
+  pub address: u8,
+  /// 0-7 on port A, 8-15 on port B
+  pub pin: u8,
+  pub edge: Edge,
+}
+
+/// A single pin of one of the chips
+#[derive(Clone, Copy)]
+pub struct Pin {
+  chip: Mcp23s08,
+  pin: u8,
+}
+
+/// A chip with its INT output connected to an on-chip pin
+#[derive(Clone, Copy)]
+struct InterruptSource {
+  chip: Mcp23s08,
+  port: gpio::Gpio,
+  /// The on-chip pin (and so the EXTI line)
+  pin: u8,
+  handler: fn(Event),
+}
+
+static mut interrupt_sources: [Option<InterruptSource>; MAX_INTERRUPT_SOURCES] = [None; MAX_INTERRUPT_SOURCES];
+/// Bit n is set when source n raised the interrupt and still has to be
+/// serviced (see service_pending)
+static mut pending: u32 = 0;
+
+/// One expander (of any of the variants, the 16-bit ones have their pins
+/// numbered 0-7 on port A and 8-15 on port B), several of them can share a CS
+/// line as long as their address pins are strapped differently
+#[derive(Clone, Copy)]
+pub struct Mcp23s08 {
+  dev: spi::SpiDevice,
+  variant: Variant,
+  /// What the address pins are strapped to (0-3, 0-7 on the MCP23S17)
+  address: u8,
+  bank: Bank,
+}
+
+impl Mcp23s08 {
+  pub const fn new(dev: spi::SpiDevice, address: u8) -> Mcp23s08 {
+    Mcp23s08 { dev: dev, variant: Variant::Mcp23s08, address: address & 0b11, bank: Bank::Separate }
+  }
+
+  /// An MCP23S17 or MCP23S18 (which has no address pins, so `address` has to
+  /// be 0), with its registers laid out as given
+  pub const fn new_16bit(dev: spi::SpiDevice, variant: Variant, address: u8, bank: Bank) -> Mcp23s08 {
+    Mcp23s08 { dev: dev, variant: variant, address: address & 0b111, bank: bank }
+  }
+
+  /// Set up the CS line, enable the hardware addressing (and select the
+  /// register layout of the 16-bit parts)
+  ///
+  /// Until HAEN is set every chip on the CS line takes the commands sent to
+  /// address 0 as its own, so this sets it in all of them at once (leaving
+  /// the rest of IOCON at its defaults).
+  pub fn initialize(&self) -> Result<(), Error> {
+    self.dev.initialize();
+
+    if self.variant == Variant::Mcp23s08 {
+      return self.broadcast(IOCON, IOCON_HAEN);
+    }
+
+    // After a reset of just the MCU the chip could be in either bank, this
+    // clears IOCON if it's in BANK=1 and GPINTENB if it's in BANK=0 (so the
+    // interrupts of port B have to be enabled again), which gets it to
+    // BANK=0 either way. It could have HAEN set already too, and then it
+    // ignores the broadcasts, so it gets the same writes at its own address.
+    self.broadcast(IOCON_BANK1, 0)?;
+
+    if self.address != 0 {
+      self.write_reg(IOCON_BANK1, 0)?;
+    }
+
+    let iocon = match self.bank {
+      Bank::Interleaved => IOCON_HAEN,
+      Bank::Separate => IOCON_HAEN | IOCON_BANK,
+    };
+
+    self.broadcast(IOCON_BANK0, iocon)?;
+
+    if self.address != 0 {
+      self.write_reg(IOCON_BANK0, iocon)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn address(&self) -> u8 {
+    self.address
+  }
+
+  pub fn variant(&self) -> Variant {
+    self.variant
+  }
+
+  /// 8 or 16
+  pub fn pin_count(&self) -> u8 {
+    if self.variant == Variant::Mcp23s08 { 8 } else { 16 }
+  }
+
+  fn opcode(&self) -> u8 {
+    OPCODE | self.address << 1
+  }
+
+  /// Write a register of every chip on the CS line that doesn't have HAEN set
+  fn broadcast(&self, reg: u8, value: u8) -> Result<(), Error> {
+    debug!("all chips: register 0x{:02x} <- 0x{:02x}", reg, value);
+
+    Ok(self.dev.write(&[OPCODE, reg, value])?)
+  }
+
+  /// Where the register (one of the constants above) of port A (0) or B (1)
+  /// is, given the variant and the layout
+  pub fn reg_address(&self, reg: u8, port: u8) -> u8 {
+    if self.variant == Variant::Mcp23s08 {
+      return reg;
+    }
+
+    match self.bank {
+      Bank::Interleaved => 2 * reg + port,
+      Bank::Separate => reg + 0x10 * port,
+    }
+  }
+
+  pub fn write_reg(&self, reg: u8, value: u8) -> Result<(), Error> {
+    debug!("chip {}: register 0x{:02x} <- 0x{:02x}", self.address, reg, value);
+
+    Ok(self.dev.write(&[self.opcode(), reg, value])?)
+  }
+
+  pub fn read_reg(&self, reg: u8) -> Result<u8, Error> {
+    let mut buf = [self.opcode() | OPCODE_READ, reg, 0xff];
+
+    self.dev.transfer_in_place(&mut buf)?;
+
+    debug!("chip {}: register 0x{:02x} -> 0x{:02x}", self.address, reg, buf[2]);
+
+    Ok(buf[2])
+  }
+
+  /// Read the register (one of the constants above) of both ports at once,
+  /// port A in the low byte (the MCP23S08 only has that one)
+  pub fn read_reg16(&self, reg: u8) -> Result<u16, Error> {
+    if self.variant == Variant::Mcp23s08 {
+      return Ok(self.read_reg(reg)? as u16);
+    }
+
+    if self.bank == Bank::Separate {
+      let a = self.read_reg(self.reg_address(reg, 0))?;
+      let b = self.read_reg(self.reg_address(reg, 1))?;
+
+      return Ok(a as u16 | (b as u16) << 8);
+    }
+
+    // Port B's register comes right after, the sequential mode reads both
+    let mut buf = [self.opcode() | OPCODE_READ, self.reg_address(reg, 0), 0xff, 0xff];
+
+    self.dev.transfer_in_place(&mut buf)?;
+
+    debug!("chip {}: registers 0x{:02x}/0x{:02x} -> 0x{:02x}{:02x}", self.address, buf[1], buf[1] + 1, buf[3], buf[2]);
+
+    Ok(buf[2] as u16 | (buf[3] as u16) << 8)
+  }
+
+  /// Write the register (one of the constants above) of both ports at once,
+  /// port A from the low byte
+  pub fn write_reg16(&self, reg: u8, value: u16) -> Result<(), Error> {
+    if self.variant == Variant::Mcp23s08 {
+      return self.write_reg(reg, value as u8);
+    }
+
+    if self.bank == Bank::Separate {
+      self.write_reg(self.reg_address(reg, 0), value as u8)?;
+      return self.write_reg(self.reg_address(reg, 1), (value >> 8) as u8);
+    }
+
+    let address = self.reg_address(reg, 0);
+
+    debug!("chip {}: registers 0x{:02x}/0x{:02x} <- 0x{:04x}", self.address, address, address + 1, value);
+
+    Ok(self.dev.write(&[self.opcode(), address, value as u8, (value >> 8) as u8])?)
+  }
+
+  fn check_pin(&self, pin: u8) -> Result<(), Error> {
+    if pin >= self.pin_count() {
+      return Err(Error::InvalidPin(pin));
+    }
+
+    Ok(())
+  }
+
+  /// Set or clear the bit of the pin in the register (one of the constants
+  /// above)
+  fn update_bit(&self, reg: u8, pin: u8, set: bool) -> Result<(), Error> {
+    self.check_pin(pin)?;
+
+    let reg = self.reg_address(reg, pin / 8);
+    let bit = pin % 8;
+    let value = self.read_reg(reg)?;
+
+    if set {
+      self.write_reg(reg, value | 1 << bit)
+    } else {
+      self.write_reg(reg, value & !(1 << bit))
+    }
+  }
+
+  pub fn set_direction(&self, pin: u8, direction: Direction) -> Result<(), Error> {
+    self.update_bit(IODIR, pin, direction == Direction::Input)
+  }
+
+  /// Enable the (100k) pull-up on an input
+  pub fn set_pull_up(&self, pin: u8, enabled: bool) -> Result<(), Error> {
+    self.update_bit(GPPU, pin, enabled)
+  }
+
+  /// Make GPIO read the opposite of the input's level
+  pub fn set_polarity(&self, pin: u8, inverted: bool) -> Result<(), Error> {
+    self.update_bit(IPOL, pin, inverted)
+  }
+
+  /// Drive an output high or low
+  pub fn set_output(&self, pin: u8, high: bool) -> Result<(), Error> {
+    self.update_bit(OLAT, pin, high)
+  }
+
+  pub fn toggle_output(&self, pin: u8) -> Result<(), Error> {
+    self.check_pin(pin)?;
+
+    let reg = self.reg_address(OLAT, pin / 8);
+    let value = self.read_reg(reg)?;
+
+    self.write_reg(reg, value ^ 1 << (pin % 8))
+  }
+
+  /// The level of the pin (after the polarity inversion, if enabled)
+  pub fn get_input(&self, pin: u8) -> Result<bool, Error> {
+    self.check_pin(pin)?;
+
+    Ok(self.read_reg(self.reg_address(GPIO, pin / 8))? & 1 << (pin % 8) != 0)
+  }
+
+  /// Make the pin raise the interrupt (see connect_interrupt)
+  pub fn enable_interrupt(&self, pin: u8, compare: Compare) -> Result<(), Error> {
+    match compare {
+      Compare::Previous => self.update_bit(INTCON, pin, false)?,
+      Compare::Default(level) => {
+        self.update_bit(DEFVAL, pin, level)?;
+        self.update_bit(INTCON, pin, true)?;
+      },
+    }
+
+    self.update_bit(GPINTEN, pin, true)
+  }
+
+  pub fn disable_interrupt(&self, pin: u8) -> Result<(), Error> {
+    self.update_bit(GPINTEN, pin, false)
+  }
+
+  /// Listen to the chip's INT output, which is connected to the given on-chip
+  /// pin, `handler` is called (from service_pending) for every pin that
+  /// raised the interrupt
+  ///
+  /// INT is made open-drain (and the on-chip pin gets a pull-up), so several
+  /// chips can share one line. On the 16-bit parts INTA and INTB are
+  /// mirrored, so either of them can be used. Returns Ok(false) if there are
+  /// already MAX_INTERRUPT_SOURCES chips connected.
+  pub fn connect_interrupt(&self, port: gpio::Gpio, pin: u8, handler: fn(Event)) -> Result<bool, Error> {
+    let iocon_address = self.reg_address(IOCON, 0);
+    let mut iocon = self.read_reg(iocon_address)? | IOCON_ODR;
+
+    if self.variant != Variant::Mcp23s08 {
+      iocon |= IOCON_MIRROR;
+    }
+
+    self.write_reg(iocon_address, iocon)?;
+
+    let source = InterruptSource { chip: *self, port: port, pin: pin, handler: handler };
+    let address = self.address;
+
+    let added = nvic::without_interrupts(|| unsafe {
+      // Either replace this chip's entry or take the first free slot
+      for slot in interrupt_sources.iter_mut() {
+        let same = match *slot {
+          Some(ref existing) => existing.chip.address == address && existing.pin == pin,
+          None => false,
+        };
+
+        if same {
+          *slot = Some(source);
+          return true;
+        }
+      }
+
+      for slot in interrupt_sources.iter_mut() {
+        if slot.is_none() {
+          *slot = Some(source);
+          return true;
+        }
+      }
+
+      false
+    });
+
+    if !added {
+      return Ok(false);
+    }
+
+    // Pull-up
+    port.enable_pin(pin);
+    port.set_pin_mode(pin, gpio::PinMode::InPP);
+
+    // Whatever was captured so far would keep INT asserted
+    self.read_reg16(INTCAP)?;
+
+    exti::listen(port, pin, exti::Edge::Falling, int_asserted);
+
+    Ok(true)
+  }
+
+  pub fn pin(&self, pin: u8) -> Pin {
+    Pin { chip: *self, pin: pin }
+  }
+
+  /// Drive all the outputs at once (port A from the low byte)
+  pub fn write_port(&self, value: u16) -> Result<(), Error> {
+    self.write_reg16(OLAT, value)
+  }
+
+  /// The levels of all the pins at once (port A in the low byte)
+  pub fn read_port(&self) -> Result<u16, Error> {
+    self.read_reg16(GPIO)
+  }
+}
+
+impl io::IoPin for Pin {
+  fn set(&self) -> Result<(), io::Error> {
+    Ok(self.chip.set_output(self.pin, true)?)
+  }
+
+  fn clear(&self) -> Result<(), io::Error> {
+    Ok(self.chip.set_output(self.pin, false)?)
+  }
+
+  fn toggle(&self) -> Result<(), io::Error> {
+    Ok(self.chip.toggle_output(self.pin)?)
+  }
+
+  fn read(&self) -> Result<bool, io::Error> {
+    Ok(self.chip.get_input(self.pin)?)
+  }
+
+  fn set_direction(&self, direction: io::Direction) -> Result<(), io::Error> {
+    Ok(self.chip.set_direction(self.pin, direction)?)
+  }
+
+  fn set_pull_up(&self, enabled: bool) -> Result<(), io::Error> {
+    Ok(self.chip.set_pull_up(self.pin, enabled)?)
+  }
+}
+
+impl io::IoPort for Mcp23s08 {
+  fn write(&self, value: u16) -> Result<(), io::Error> {
+    Ok(self.write_port(value)?)
+  }
+
+  fn read(&self) -> Result<u16, io::Error> {
+    Ok(self.read_port()?)
+  }
+}
+
+/// Read INTF and INTCAP (which releases INT) and notify the handler, returns
+/// false if the bus was busy
+fn service(source: &InterruptSource) -> bool {
+  // INTF stays as it is until INTCAP is read, so it's fine to start over if
+  // the bus is busy in between
+  let flags = source.chip.read_reg16(INTF).and_then(|intf| {
+    source.chip.read_reg16(INTCAP).map(|intcap| (intf, intcap))
+  });
+
+  let (intf, intcap) = match flags {
+    Ok(flags) => flags,
+    Err(_) => return false,
+  };
+
+  for pin in 0..source.chip.pin_count() {
+    if intf & 1 << pin == 0 {
+      continue;
+    }
+
+    (source.handler)(Event {
+      address: source.chip.address,
+      pin: pin,
+      edge: if intcap & 1 << pin != 0 { Edge::Rising } else { Edge::Falling },
+    });
+  }
+
+  true
+}
+
+/// The bits (in `pending`) of the sources connected to the given line
+fn sources_on(line: u8) -> u32 {
+  let mut bits = 0;
+
+  for index in 0..MAX_INTERRUPT_SOURCES {
+    match unsafe { interrupt_sources[index] } {
+      Some(source) if source.pin == line => bits |= 1 << index,
+      _ => (),
+    }
+  }
+
+  bits
+}
+
+/// The EXTI handler of the INT lines, the chips are only read later on (see
+/// service_pending), as that takes whole SPI transactions
+fn int_asserted(line: u8) {
+  unsafe {
+    pending |= sources_on(line);
+  }
+}
+
+/// Read the chips which raised the interrupt and run their handlers
+///
+/// This has to be run periodically from the main loop (the interrupt
+/// handlers only take note of which chips need it). The ones that can't be
+/// read because the bus is busy are left for the next time.
+pub fn service_pending() {
+  let pending_now = nvic::without_interrupts(|| unsafe {
+    let pending_now = pending;
+    pending = 0;
+    pending_now
+  });
+
+  for index in 0..MAX_INTERRUPT_SOURCES {
+    if pending_now & 1 << index == 0 {
+      continue;
+    }
+
+    let source = match unsafe { interrupt_sources[index] } {
+      Some(source) => source,
+      None => continue,
+    };
+
+    let again = if service(&source) {
+      // Another chip sharing the line could've pulled it down while this one
+      // was being serviced, which wouldn't make another edge
+      if source.port.read_pin(source.pin) { 0 } else { sources_on(source.pin) }
+    } else {
+      1 << index
+    };
+
+    nvic::without_interrupts(|| unsafe { pending |= again });
+  }
 }
 
 /*
@@ -0,0 +1,161 @@
+//
+// nvic.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:04:53 +0000 (UTC)
+//
+
+use mmio;
+
+/// Interrupt Set-Enable Registers
+const NVIC_ISER: u32 = 0xE000_E100;
+/// Interrupt Clear-Enable Registers
+const NVIC_ICER: u32 = 0xE000_E180;
+/// Interrupt Clear-Pending Registers
+const NVIC_ICPR: u32 = 0xE000_E280;
+/// Interrupt Priority Registers (one byte per interrupt, only the upper four
+/// bits are implemented)
+const NVIC_IPR: u32 = 0xE000_E400;
+
+/// Position of the peripheral interrupts in the vector table (that's the
+/// number of the interrupt, not the exception number)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Irq {
+  EXTI0 = 6,
+  EXTI1 = 7,
+  EXTI2 = 8,
+  EXTI3 = 9,
+  EXTI4 = 10,
+  DMA1_CHANNEL1 = 11,
+  DMA1_CHANNEL2 = 12,
+  DMA1_CHANNEL3 = 13,
+  DMA1_CHANNEL4 = 14,
+  DMA1_CHANNEL5 = 15,
+  DMA1_CHANNEL6 = 16,
+  DMA1_CHANNEL7 = 17,
+  EXTI9_5 = 23,
+  TIM1_UP = 25,
+  TIM1_CC = 27,
+  TIM2 = 28,
+  TIM3 = 29,
+  TIM4 = 30,
+  SPI1 = 35,
+  SPI2 = 36,
+  USART1 = 37,
+  USART2 = 38,
+  USART3 = 39,
+  EXTI15_10 = 40,
+}
+
+pub fn enable_irq(irq: Irq) {
+  let irq = irq as u32;
+
+  mmio::write(NVIC_ISER + 4 * (irq / 32), 1 << (irq % 32));
+}
+
+pub fn disable_irq(irq: Irq) {
+  let irq = irq as u32;
+
+  mmio::write(NVIC_ICER + 4 * (irq / 32), 1 << (irq % 32));
+}
+
+pub fn clear_pending(irq: Irq) {
+  let irq = irq as u32;
+
+  mmio::write(NVIC_ICPR + 4 * (irq / 32), 1 << (irq % 32));
+}
+
+/// 0 is the highest priority, 15 is the lowest
+pub fn set_priority(irq: Irq, priority: u8) {
+  mmio::write_u8(NVIC_IPR + irq as u32, (priority & 0xf) << 4);
+}
+
+/// Whether the (maskable) interrupts are disabled at the moment
+pub fn interrupts_disabled() -> bool {
+  primask() & 1 != 0
+}
+
+/// Whether this runs from an exception or an interrupt handler
+///
+/// The interrupts all have the same priority, so nothing else gets to run
+/// (eg. drain a buffer) until the handler returns.
+pub fn in_handler() -> bool {
+  ipsr() & 0x1ff != 0
+}
+
+/// Run `f` with all (maskable) interrupts disabled, restoring the previous
+/// state afterwards (so it's fine to nest these)
+pub fn without_interrupts<F, R>(f: F) -> R where F: FnOnce() -> R {
+  let primask = primask();
+
+  disable_interrupts();
+
+  let ret = f();
+
+  // Only re-enable them if they were enabled when we came in
+  if primask & 1 == 0 {
+    enable_interrupts();
+  }
+
+  ret
+}
+
+// The registers are only there on the target, the unit tests (built for the
+// host) get a CPU with the interrupts enabled and never in a handler
+
+#[cfg(target_arch = "arm")]
+fn primask() -> u32 {
+  let primask: u32;
+
+  unsafe {
+    asm!("mrs $0, PRIMASK" : "=r"(primask) : : : "volatile");
+  }
+
+  primask
+}
+
+#[cfg(target_arch = "arm")]
+fn ipsr() -> u32 {
+  let ipsr: u32;
+
+  unsafe {
+    asm!("mrs $0, IPSR" : "=r"(ipsr) : : : "volatile");
+  }
+
+  ipsr
+}
+
+#[cfg(target_arch = "arm")]
+fn disable_interrupts() {
+  unsafe {
+    asm!("cpsid i" : : : "memory" : "volatile");
+  }
+}
+
+#[cfg(target_arch = "arm")]
+fn enable_interrupts() {
+  unsafe {
+    asm!("cpsie i" : : : "memory" : "volatile");
+  }
+}
+
+#[cfg(not(target_arch = "arm"))]
+fn primask() -> u32 {
+  0
+}
+
+#[cfg(not(target_arch = "arm"))]
+fn ipsr() -> u32 {
+  0
+}
+
+#[cfg(not(target_arch = "arm"))]
+fn disable_interrupts() {}
+
+#[cfg(not(target_arch = "arm"))]
+fn enable_interrupts() {}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
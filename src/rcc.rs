@@ -37,10 +37,28 @@ const RCC_CFGR_PLLMUL: u32 = 0b1111 << 18;
 const RCC_CFGR_MCO: u32 = 0b1111 << 24;
 /// RCC Clock Interrupt Register address
 const RCC_CIR: u32 = RCC + 0x08;
+/// Address of the AHBENR register
+const RCC_AHBENR: u32 = RCC + 0x14;
+/// Bit that is in charge of enabling/disabling DMA1
+const RCC_AHBENR_DMA1EN: u32 = 1 << 0;
 /// Address of the APB1ENR register
 const RCC_APB1ENR: u32 = RCC + 0x1c;
+/// Bit that is in charge of enabling/disabling TIM2
+const RCC_APB1ENR_TIM2EN: u32 = 1 << 0;
+/// Bit that is in charge of enabling/disabling TIM3
+const RCC_APB1ENR_TIM3EN: u32 = 1 << 1;
+/// Bit that is in charge of enabling/disabling TIM4
+const RCC_APB1ENR_TIM4EN: u32 = 1 << 2;
+/// Bit that is in charge of enabling/disabling SPI2
+const RCC_APB1ENR_SPI2EN: u32 = 1 << 14;
 /// Bit that is in charge of enabling/disabling the USART2 port
 const RCC_APB1ENR_USART2EN: u32 = 1 << 17;
+/// Bit that is in charge of enabling/disabling the USART3 port
+const RCC_APB1ENR_USART3EN: u32 = 1 << 18;
+/// Bit that is in charge of enabling/disabling I2C1
+const RCC_APB1ENR_I2C1EN: u32 = 1 << 21;
+/// Bit that is in charge of enabling/disabling I2C2
+const RCC_APB1ENR_I2C2EN: u32 = 1 << 22;
 /// Address of the APB2ENR register
 const RCC_APB2ENR: u32 = RCC + 0x18;
 /// Bit that is in charge of enabling/disabling the GPIOA port
@@ -57,8 +75,16 @@ const RCC_APB2ENR_IOPEEN: u32 = 1 << 6;
 const RCC_APB2ENR_IOPFEN: u32 = 1 << 7;
 /// Bit that is in charge of enabling/disabling the GPIOD port
 const RCC_APB2ENR_IOPGEN: u32 = 1 << 8;
+/// Bit that is in charge of enabling/disabling ADC1
+const RCC_APB2ENR_ADC1EN: u32 = 1 << 9;
+/// Bit that is in charge of enabling/disabling ADC2
+const RCC_APB2ENR_ADC2EN: u32 = 1 << 10;
+/// Bit that is in charge of enabling/disabling TIM1
+const RCC_APB2ENR_TIM1EN: u32 = 1 << 11;
 /// Bit that is in charge of enabling/disabling SPI1
 const RCC_APB2ENR_SPI1EN: u32 = 1 << 12;
+/// Bit that is in charge of enabling/disabling the USART1 port
+const RCC_APB2ENR_USART1EN: u32 = 1 << 14;
 /// Bit that is in charge of setting the alternate function of the IO clock
 const RCC_APB2ENR_AFIOEN: u32 = 1 << 0;
 
@@ -76,7 +102,17 @@ const FLASH_ACR_LATENCY: u32 = 0b111 << 0;
 const FLASH_ACR_PRFBTE: u32 = 0b1 << 4;
 
 pub enum Periph {
+  ahb_dma1,
+  apb1_i2c1,
+  apb1_i2c2,
+  apb1_spi2,
+  apb1_tim2,
+  apb1_tim3,
+  apb1_tim4,
   apb1_usart2,
+  apb1_usart3,
+  apb2_adc1,
+  apb2_adc2,
   apb2_afio,
   apb2_gpioa,
   apb2_gpiob,
@@ -86,6 +122,8 @@ pub enum Periph {
   apb2_gpiof,
   apb2_gpiog,
   apb2_spi1,
+  apb2_tim1,
+  apb2_usart1,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -94,14 +132,31 @@ pub enum Clock {
   HCLK,
   PCLK1,
   PCLK2,
-  // TODO SDIOCLK, FSMCCLK, FCLK, TIMXCLK, ADCCLK etc.
+  ADCCLK,
+  /// Clock of the timers on APB1 (TIM2-TIM7)
+  TIMCLK1,
+  /// Clock of the timers on APB2 (TIM1, TIM8)
+  TIMCLK2,
+  // TODO SDIOCLK, FSMCCLK, FCLK etc.
 }
 
 pub fn enable(periph: Periph) {
   let (reg, bit) = match periph {
+    Periph::ahb_dma1    => (RCC_AHBENR, RCC_AHBENR_DMA1EN),
+    Periph::apb1_i2c1   => (RCC_APB1ENR, RCC_APB1ENR_I2C1EN),
+    Periph::apb1_i2c2   => (RCC_APB1ENR, RCC_APB1ENR_I2C2EN),
+    Periph::apb1_spi2   => (RCC_APB1ENR, RCC_APB1ENR_SPI2EN),
+    Periph::apb1_tim2   => (RCC_APB1ENR, RCC_APB1ENR_TIM2EN),
+    Periph::apb1_tim3   => (RCC_APB1ENR, RCC_APB1ENR_TIM3EN),
+    Periph::apb1_tim4   => (RCC_APB1ENR, RCC_APB1ENR_TIM4EN),
     Periph::apb1_usart2 => (RCC_APB1ENR, RCC_APB1ENR_USART2EN),
+    Periph::apb1_usart3 => (RCC_APB1ENR, RCC_APB1ENR_USART3EN),
+    Periph::apb2_adc1   => (RCC_APB2ENR, RCC_APB2ENR_ADC1EN),
+    Periph::apb2_adc2   => (RCC_APB2ENR, RCC_APB2ENR_ADC2EN),
     Periph::apb2_afio   => (RCC_APB2ENR, RCC_APB2ENR_AFIOEN),
     Periph::apb2_spi1   => (RCC_APB2ENR, RCC_APB2ENR_SPI1EN),
+    Periph::apb2_tim1   => (RCC_APB2ENR, RCC_APB2ENR_TIM1EN),
+    Periph::apb2_usart1 => (RCC_APB2ENR, RCC_APB2ENR_USART1EN),
     Periph::apb2_gpioa  => (RCC_APB2ENR, RCC_APB2ENR_IOPAEN),
     Periph::apb2_gpiob  => (RCC_APB2ENR, RCC_APB2ENR_IOPBEN),
     Periph::apb2_gpioc  => (RCC_APB2ENR, RCC_APB2ENR_IOPCEN),
@@ -139,6 +194,8 @@ pub fn initialize_clocks() {
   mmio::set_bits(RCC_CFGR, 0b000 << 11);
   // Set PPRE1 division to 2 (ie. PCLK1 = HCLK / 2 (can't exceed 36MHz))
   mmio::set_bits(RCC_CFGR, 0b100 << 8);
+  // Set ADCPRE division to 6 (ie. ADCCLK = PCLK2 / 6 (can't exceed 14MHz))
+  mmio::set_bits(RCC_CFGR, 0b10 << 14);
 
   // Enable flash's prefetch buffer
   mmio::set_bits(FLASH_ACR, FLASH_ACR_PRFBTE);
@@ -189,12 +246,23 @@ pub fn get_clock_speed(clock: Clock) -> u32 {
   let ppre2 = (mmio::read(RCC_CFGR) & RCC_CFGR_PPRE2) >> 11;
   let pclk2 = hclk >> (((ppre2 & 0b100) >> 2) * ((ppre2 & 0b11) + 1));
 
+  // 0b00 => 2, 0b01 => 4, 0b10 => 6, 0b11 => 8
+  let adcpre = (mmio::read(RCC_CFGR) & RCC_CFGR_ADCPRE) >> 14;
+  let adcclk = pclk2 / (2 * (adcpre + 1));
+
+  // The timers get twice the APB clock, unless its prescaler is 1
+  let timclk1 = if ppre1 & 0b100 == 0 { pclk1 } else { pclk1 * 2 };
+  let timclk2 = if ppre2 & 0b100 == 0 { pclk2 } else { pclk2 * 2 };
+
   // Return the requested value here so that we exhaust all input patterns
   return match clock {
-    Clock::SYSCLK => sysclk,
-    Clock::HCLK   => hclk,
-    Clock::PCLK1  => pclk1,
-    Clock::PCLK2  => pclk2,
+    Clock::SYSCLK  => sysclk,
+    Clock::HCLK    => hclk,
+    Clock::PCLK1   => pclk1,
+    Clock::PCLK2   => pclk2,
+    Clock::ADCCLK  => adcclk,
+    Clock::TIMCLK1 => timclk1,
+    Clock::TIMCLK2 => timclk2,
   };
 }
 
@@ -0,0 +1,613 @@
+//
+// readline.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:08:08 +0000 (UTC)
+//
+
+use usart;
+use cmd;
+
+/// The longest line that can be entered (and remembered in the history)
+pub const LINE_SIZE: usize = 80;
+/// How many of the most recent lines are remembered
+const HISTORY_SIZE: usize = 8;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_E: u8 = 0x05;
+const BELL: u8 = 0x07;
+const BACKSPACE: u8 = 0x08;
+const TAB: u8 = 0x09;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+/// Where the keys come from and where they're echoed to
+pub trait Terminal {
+  /// Wait for a byte
+  fn get_byte(&self) -> u8;
+  fn send_byte(&self, byte: u8);
+}
+
+impl Terminal for usart::Usart {
+  fn get_byte(&self) -> u8 {
+    usart::Usart::get_byte(self)
+  }
+
+  fn send_byte(&self, byte: u8) {
+    usart::Usart::send_byte(self, byte)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Key {
+  Up,
+  Down,
+  Left,
+  Right,
+  Home,
+  End,
+  Delete,
+}
+
+/// Reads lines from a terminal, letting the user move around the line, edit
+/// it, go through the previously entered lines and complete command names
+pub struct Editor {
+  history: [[u8; LINE_SIZE]; HISTORY_SIZE],
+  history_lens: [usize; HISTORY_SIZE],
+  /// Where the next line will be stored
+  history_next: usize,
+  /// How many of the entries are actually used
+  history_count: usize,
+  /// The last byte received (to recognize CR LF across lines)
+  last: u8,
+}
+
+/// The line that's being edited, along with where the terminal's cursor is
+struct Line<'a, T: Terminal + Copy> {
+  port: T,
+  buf: &'a mut [u8],
+  len: usize,
+  pos: usize,
+}
+
+impl<'a, T: Terminal + Copy> Line<'a, T> {
+  fn put(&self, byte: u8) {
+    self.port.send_byte(byte);
+  }
+
+  fn put_range(&self, from: usize, to: usize) {
+    for i in from..to {
+      self.put(self.buf[i]);
+    }
+  }
+
+  /// Move the terminal's cursor left by `n` characters
+  fn back(&self, n: usize) {
+    for _ in 0..n {
+      self.put(BACKSPACE);
+    }
+  }
+
+  fn insert(&mut self, byte: u8) {
+    if self.len >= self.buf.len() {
+      self.put(BELL);
+      return;
+    }
+
+    let mut i = self.len;
+    while i > self.pos {
+      self.buf[i] = self.buf[i - 1];
+      i -= 1;
+    }
+
+    self.buf[self.pos] = byte;
+    self.len += 1;
+
+    // Redraw everything from the new character onwards and get back to
+    // where the cursor should be
+    self.put_range(self.pos, self.len);
+    self.pos += 1;
+    self.back(self.len - self.pos);
+  }
+
+  /// Remove the characters in [from, to), leaving the cursor at `from`
+  fn remove(&mut self, from: usize, to: usize) {
+    if from >= to {
+      return;
+    }
+
+    let n = to - from;
+
+    self.back(self.pos - from);
+    self.pos = from;
+
+    for i in to..self.len {
+      self.buf[i - n] = self.buf[i];
+    }
+    self.len -= n;
+
+    // Redraw the rest of the line and blank out what's left of the old one
+    self.put_range(self.pos, self.len);
+    for _ in 0..n {
+      self.put(b' ');
+    }
+    self.back(self.len - self.pos + n);
+  }
+
+  /// Replace the whole line with `content`, leaving the cursor at the end
+  fn replace(&mut self, content: &[u8]) {
+    let old_len = self.len;
+
+    self.back(self.pos);
+
+    self.len = 0;
+    for &byte in content.iter().take(self.buf.len()) {
+      self.buf[self.len] = byte;
+      self.len += 1;
+    }
+    self.pos = self.len;
+
+    self.put_range(0, self.len);
+
+    if old_len > self.len {
+      for _ in self.len..old_len {
+        self.put(b' ');
+      }
+      self.back(old_len - self.len);
+    }
+  }
+
+  fn move_to(&mut self, pos: usize) {
+    if pos < self.pos {
+      self.back(self.pos - pos);
+    } else {
+      self.put_range(self.pos, pos);
+    }
+
+    self.pos = pos;
+  }
+
+  /// Where the word that ends at the cursor starts
+  fn word_start(&self) -> usize {
+    let mut start = self.pos;
+
+    while start > 0 && self.buf[start - 1] != b' ' {
+      start -= 1;
+    }
+
+    start
+  }
+
+  /// Complete the word under the cursor using the command names (for the
+  /// first word) or the keywords the command understands (for the rest)
+  fn complete(&mut self) {
+    let start = self.word_start();
+    let prefix_len = self.pos - start;
+    let first_word = self.buf[..start].iter().all(|&byte| byte == b' ');
+
+    let keywords = if first_word {
+      None
+    } else {
+      let command_end = self.buf[..self.len].iter().position(|&byte| byte == b' ').unwrap_or(self.len);
+
+      match cmd::lookup_keywords(as_str(&self.buf[..command_end])) {
+        Some(keywords) => Some(keywords),
+        None => {
+          self.put(BELL);
+          return;
+        },
+      }
+    };
+
+    // Find how many candidates match and the longest prefix they share
+    let mut matches = 0;
+    let mut common: &[u8] = &[];
+
+    {
+      let prefix = &self.buf[start..self.pos];
+      let mut consider = |candidate: &'static str| {
+        let candidate = candidate.as_bytes();
+
+        if !candidate.starts_with(prefix) {
+          return;
+        }
+
+        if matches == 0 {
+          common = candidate;
+        } else {
+          let same = common.iter().zip(candidate.iter()).take_while(|&(a, b)| a == b).count();
+          common = &common[..same];
+        }
+
+        matches += 1;
+      };
+
+      match keywords {
+        None => {
+          for &(name, _, _) in cmd::commands {
+            consider(name);
+          }
+        },
+        Some(keywords) => {
+          for &keyword in keywords {
+            consider(keyword);
+          }
+        },
+      }
+    }
+
+    if matches == 0 {
+      self.put(BELL);
+      return;
+    }
+
+    for &byte in &common[prefix_len..] {
+      self.insert(byte);
+    }
+
+    if matches == 1 {
+      if self.pos == self.len {
+        self.insert(b' ');
+      }
+    } else if common.len() == prefix_len {
+      // Ambiguous and nothing more could be filled in
+      self.put(BELL);
+    }
+  }
+}
+
+fn as_str(bytes: &[u8]) -> &str {
+  // Only printable ASCII ever makes it into the line
+  unsafe { ::core::str::from_utf8_unchecked(bytes) }
+}
+
+/// Decode what follows an escape character (VT100/xterm sequences)
+fn read_escape<T: Terminal>(port: T) -> Option<Key> {
+  match port.get_byte() {
+    b'[' => (),
+    b'O' => {
+      return match port.get_byte() {
+        b'H' => Some(Key::Home),
+        b'F' => Some(Key::End),
+        _ => None,
+      };
+    },
+    _ => return None,
+  }
+
+  let mut param = 0u32;
+
+  loop {
+    let byte = port.get_byte();
+
+    match byte {
+      b'0'...b'9' => param = param.wrapping_mul(10).wrapping_add((byte - b'0') as u32),
+      b'A' => return Some(Key::Up),
+      b'B' => return Some(Key::Down),
+      b'C' => return Some(Key::Right),
+      b'D' => return Some(Key::Left),
+      b'H' => return Some(Key::Home),
+      b'F' => return Some(Key::End),
+      b'~' => {
+        return match param {
+          1 | 7 => Some(Key::Home),
+          4 | 8 => Some(Key::End),
+          3 => Some(Key::Delete),
+          _ => None,
+        };
+      },
+      // Some other final byte - a sequence we don't care about
+      0x40...0x7e => return None,
+      _ => (),
+    }
+  }
+}
+
+impl Editor {
+  pub fn new() -> Editor {
+    Editor {
+      history: [[0; LINE_SIZE]; HISTORY_SIZE],
+      history_lens: [0; HISTORY_SIZE],
+      history_next: 0,
+      history_count: 0,
+      last: 0,
+    }
+  }
+
+  /// The n-th most recent line (0 being the last one entered)
+  fn history_entry(&self, n: usize) -> &[u8] {
+    let i = (self.history_next + HISTORY_SIZE - 1 - n) % HISTORY_SIZE;
+
+    &self.history[i][..self.history_lens[i]]
+  }
+
+  fn remember(&mut self, line: &[u8]) {
+    if line.is_empty() || (self.history_count > 0 && self.history_entry(0) == line) {
+      return;
+    }
+
+    let i = self.history_next;
+
+    self.history[i][..line.len()].copy_from_slice(line);
+    self.history_lens[i] = line.len();
+
+    self.history_next = (self.history_next + 1) % HISTORY_SIZE;
+
+    if self.history_count < HISTORY_SIZE {
+      self.history_count += 1;
+    }
+  }
+
+  /// Print the prompt and read a line into `buf`, returning its length
+  ///
+  /// Only printable ASCII characters are accepted, and no more than
+  /// LINE_SIZE of them.
+  pub fn read_line<T: Terminal + Copy>(&mut self, port: T, prompt: &str, buf: &mut [u8]) -> usize {
+    let cap = if buf.len() < LINE_SIZE { buf.len() } else { LINE_SIZE };
+
+    for byte in prompt.bytes() {
+      port.send_byte(byte);
+    }
+
+    let len = self.edit(port, &mut buf[..cap]);
+
+    port.send_byte(b'\r');
+    port.send_byte(b'\n');
+
+    self.remember(&buf[..len]);
+
+    len
+  }
+
+  fn edit<T: Terminal + Copy>(&mut self, port: T, buf: &mut [u8]) -> usize {
+    let mut line = Line { port: port, buf: buf, len: 0, pos: 0 };
+
+    // What was typed before going up the history
+    let mut scratch = [0u8; LINE_SIZE];
+    let mut scratch_len = 0;
+    // 0 - the line being typed in, n - the n-th most recent line
+    let mut browsing = 0;
+
+    loop {
+      let byte = port.get_byte();
+      let last = self.last;
+
+      self.last = byte;
+
+      // Terminals can send either of CR, LF or CR LF on enter
+      if byte == b'\n' && last == b'\r' {
+        continue;
+      }
+
+      let key = match byte {
+        b'\r' | b'\n' => break,
+        ESC => read_escape(port),
+        CTRL_A => Some(Key::Home),
+        CTRL_E => Some(Key::End),
+        CTRL_U => {
+          let pos = line.pos;
+          line.remove(0, pos);
+          None
+        },
+        CTRL_W => {
+          // Skip the spaces right before the cursor, then the word itself
+          let mut start = line.pos;
+          while start > 0 && line.buf[start - 1] == b' ' {
+            start -= 1;
+          }
+          while start > 0 && line.buf[start - 1] != b' ' {
+            start -= 1;
+          }
+
+          let pos = line.pos;
+          line.remove(start, pos);
+          None
+        },
+        BACKSPACE | DEL => {
+          if line.pos > 0 {
+            let pos = line.pos;
+            line.remove(pos - 1, pos);
+          }
+          None
+        },
+        TAB => {
+          line.complete();
+          None
+        },
+        0x20...0x7e => {
+          line.insert(byte);
+          None
+        },
+        _ => None,
+      };
+
+      match key {
+        Some(Key::Left) if line.pos > 0 => {
+          let pos = line.pos;
+          line.move_to(pos - 1);
+        },
+        Some(Key::Right) if line.pos < line.len => {
+          let pos = line.pos;
+          line.move_to(pos + 1);
+        },
+        Some(Key::Home) => line.move_to(0),
+        Some(Key::End) => {
+          let len = line.len;
+          line.move_to(len);
+        },
+        Some(Key::Delete) if line.pos < line.len => {
+          let pos = line.pos;
+          line.remove(pos, pos + 1);
+        },
+        Some(Key::Up) if browsing < self.history_count => {
+          if browsing == 0 {
+            scratch[..line.len].copy_from_slice(&line.buf[..line.len]);
+            scratch_len = line.len;
+          }
+
+          browsing += 1;
+          line.replace(self.history_entry(browsing - 1));
+        },
+        Some(Key::Down) if browsing > 0 => {
+          browsing -= 1;
+
+          if browsing == 0 {
+            line.replace(&scratch[..scratch_len]);
+          } else {
+            line.replace(self.history_entry(browsing - 1));
+          }
+        },
+        _ => (),
+      }
+    }
+
+    line.len
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::{Cell, RefCell};
+
+  /// Feeds the editor the given keys and collects what it echoes
+  struct Script {
+    input: &'static [u8],
+    next: Cell<usize>,
+    output: RefCell<Vec<u8>>,
+  }
+
+  impl<'a> Terminal for &'a Script {
+    fn get_byte(&self) -> u8 {
+      let i = self.next.get();
+      self.next.set(i + 1);
+      self.input[i]
+    }
+
+    fn send_byte(&self, byte: u8) {
+      self.output.borrow_mut().push(byte);
+    }
+  }
+
+  fn script(input: &'static [u8]) -> Script {
+    Script { input: input, next: Cell::new(0), output: RefCell::new(Vec::new()) }
+  }
+
+  /// Read one line, returns it as a String
+  fn read(editor: &mut Editor, keys: &Script) -> String {
+    let mut buf = [0u8; LINE_SIZE];
+    let len = editor.read_line(keys, "", &mut buf);
+
+    String::from_utf8(buf[..len].to_vec()).unwrap()
+  }
+
+  #[test]
+  fn plain_line() {
+    let mut editor = Editor::new();
+    let keys = script(b"gpio set A 5\r");
+
+    assert_eq!(read(&mut editor, &keys), "gpio set A 5");
+    assert_eq!(&keys.output.borrow()[..], b"gpio set A 5\r\n");
+  }
+
+  #[test]
+  fn crlf_is_one_enter() {
+    let mut editor = Editor::new();
+    let keys = script(b"ab\r\ncd\n");
+
+    assert_eq!(read(&mut editor, &keys), "ab");
+    assert_eq!(read(&mut editor, &keys), "cd");
+  }
+
+  #[test]
+  fn inserts_and_deletes_in_the_middle() {
+    let mut editor = Editor::new();
+    // Left twice, then X between 'b' and 'c'
+    let keys = script(b"abcd\x1b[D\x1b[DX\r");
+
+    assert_eq!(read(&mut editor, &keys), "abXcd");
+
+    // Backspace over the 'X' and 'b', then Delete the 'c'
+    let keys = script(b"abXcd\x1b[D\x1b[D\x08\x08\x1b[3~\r");
+
+    assert_eq!(read(&mut editor, &keys), "ad");
+  }
+
+  #[test]
+  fn home_end_and_kill() {
+    let mut editor = Editor::new();
+    // Ctrl-A, '>', Ctrl-E, '<', then Ctrl-W drops the last word
+    let keys = script(b"one two\x01>\x05<\x17\r");
+
+    assert_eq!(read(&mut editor, &keys), ">one ");
+
+    // Ctrl-U drops everything before the cursor
+    let keys = script(b"abc\x1b[Dxyz\x15\r");
+
+    assert_eq!(read(&mut editor, &keys), "c");
+  }
+
+  #[test]
+  fn line_is_capped() {
+    let mut editor = Editor::new();
+    let keys = script(b"abcdef\r");
+    let mut buf = [0u8; 4];
+
+    assert_eq!(editor.read_line(&keys, "", &mut buf), 4);
+    assert_eq!(&buf, b"abcd");
+    assert!(keys.output.borrow().contains(&BELL));
+  }
+
+  #[test]
+  fn goes_through_history() {
+    let mut editor = Editor::new();
+
+    read(&mut editor, &script(b"first\r"));
+    read(&mut editor, &script(b"second\r"));
+    // The same line twice in a row is only remembered once
+    read(&mut editor, &script(b"second\r"));
+
+    assert_eq!(read(&mut editor, &script(b"\x1b[A\r")), "second");
+    assert_eq!(read(&mut editor, &script(b"\x1b[A\x1b[A\r")), "first");
+    // Going back down restores what was being typed
+    assert_eq!(read(&mut editor, &script(b"new\x1b[A\x1b[B\r")), "new");
+  }
+
+  #[test]
+  fn history_forgets_the_oldest() {
+    let mut editor = Editor::new();
+
+    for i in 0..HISTORY_SIZE + 2 {
+      editor.remember(format!("line {}", i).as_bytes());
+    }
+
+    assert_eq!(editor.history_count, HISTORY_SIZE);
+    assert_eq!(editor.history_entry(0), format!("line {}", HISTORY_SIZE + 1).as_bytes());
+    assert_eq!(editor.history_entry(HISTORY_SIZE - 1), b"line 2");
+  }
+
+  #[test]
+  fn completes_commands_and_keywords() {
+    let mut editor = Editor::new();
+
+    assert_eq!(read(&mut editor, &script(b"gp\t\r")), "gpio ");
+    assert_eq!(read(&mut editor, &script(b"gpio cl\t\r")), "gpio clear ");
+
+    // "loadb" and "log" only share "lo"
+    let keys = script(b"l\t\r");
+
+    assert_eq!(read(&mut editor, &keys), "lo");
+
+    let keys = script(b"lo\t\r");
+
+    assert_eq!(read(&mut editor, &keys), "lo");
+    assert!(keys.output.borrow().contains(&BELL));
+  }
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
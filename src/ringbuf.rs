@@ -0,0 +1,166 @@
+//
+// ringbuf.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:04:53 +0000 (UTC)
+//
+
+use core::ptr;
+
+/// How many bytes a ring buffer can hold (one slot is always kept free)
+pub const RINGBUF_SIZE: usize = 256;
+
+/// A byte FIFO that is safe to use with one producer and one consumer, where
+/// one of them may be an interrupt handler (the indices are only ever written
+/// by their owner, and always through volatile accesses)
+pub struct RingBuf {
+  buf: [u8; RINGBUF_SIZE],
+  /// Where the next byte will be written (owned by the producer)
+  head: usize,
+  /// Where the next byte will be read from (owned by the consumer)
+  tail: usize,
+}
+
+impl RingBuf {
+  pub const fn new() -> RingBuf {
+    RingBuf { buf: [0; RINGBUF_SIZE], head: 0, tail: 0 }
+  }
+
+  fn head(&self) -> usize {
+    unsafe { ptr::read_volatile(&self.head) }
+  }
+
+  fn tail(&self) -> usize {
+    unsafe { ptr::read_volatile(&self.tail) }
+  }
+
+  pub fn len(&self) -> usize {
+    (self.head() + RINGBUF_SIZE - self.tail()) % RINGBUF_SIZE
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.head() == self.tail()
+  }
+
+  pub fn is_full(&self) -> bool {
+    (self.head() + 1) % RINGBUF_SIZE == self.tail()
+  }
+
+  /// Returns false (and drops the byte) if there's no space left
+  pub fn push(&mut self, byte: u8) -> bool {
+    if self.is_full() {
+      return false;
+    }
+
+    let head = self.head();
+    self.buf[head] = byte;
+
+    unsafe {
+      ptr::write_volatile(&mut self.head, (head + 1) % RINGBUF_SIZE);
+    }
+
+    true
+  }
+
+  pub fn pop(&mut self) -> Option<u8> {
+    if self.is_empty() {
+      return None;
+    }
+
+    let tail = self.tail();
+    let byte = self.buf[tail];
+
+    unsafe {
+      ptr::write_volatile(&mut self.tail, (tail + 1) % RINGBUF_SIZE);
+    }
+
+    Some(byte)
+  }
+
+  pub fn clear(&mut self) {
+    let head = self.head();
+
+    unsafe {
+      ptr::write_volatile(&mut self.tail, head);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pops_in_order() {
+    let mut ring = RingBuf::new();
+
+    assert!(ring.is_empty());
+    assert!(ring.push(1));
+    assert!(ring.push(2));
+    assert!(ring.push(3));
+    assert_eq!(ring.len(), 3);
+
+    assert_eq!(ring.pop(), Some(1));
+    assert_eq!(ring.pop(), Some(2));
+    assert_eq!(ring.pop(), Some(3));
+    assert_eq!(ring.pop(), None);
+    assert!(ring.is_empty());
+  }
+
+  #[test]
+  fn keeps_one_slot_free() {
+    let mut ring = RingBuf::new();
+
+    for i in 0..RINGBUF_SIZE - 1 {
+      assert!(ring.push(i as u8));
+    }
+
+    assert!(ring.is_full());
+    assert_eq!(ring.len(), RINGBUF_SIZE - 1);
+    assert!(!ring.push(0xff));
+
+    assert_eq!(ring.pop(), Some(0));
+    assert!(!ring.is_full());
+    assert!(ring.push(0xff));
+  }
+
+  #[test]
+  fn wraps_around() {
+    let mut ring = RingBuf::new();
+
+    // Move the indices close to the end first
+    for _ in 0..RINGBUF_SIZE - 2 {
+      ring.push(0);
+      ring.pop();
+    }
+
+    for i in 0..10 {
+      assert!(ring.push(i));
+    }
+
+    assert_eq!(ring.len(), 10);
+
+    for i in 0..10 {
+      assert_eq!(ring.pop(), Some(i));
+    }
+
+    assert!(ring.is_empty());
+  }
+
+  #[test]
+  fn clear_drops_everything() {
+    let mut ring = RingBuf::new();
+
+    ring.push(1);
+    ring.push(2);
+    ring.clear();
+
+    assert!(ring.is_empty());
+    assert_eq!(ring.pop(), None);
+  }
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
@@ -6,7 +6,15 @@
 // Created on: 01 Mar 2017 20:07:34 +0100 (CET)
 //
 
+use core::ptr;
+use core::slice;
+
 use gpio;
+use rcc;
+use nvic;
+use dma;
+use exti;
+use ringbuf::RingBuf;
 
 #[repr(packed)]
 struct SPI_register_map {
@@ -21,6 +29,10 @@ struct SPI_register_map {
   I2SPR: u32,
 }
 
+/// Hardware CRC calculation enable
+const SPI_CR1_CRCEN: u32 = 1 << 13;
+/// Send the CRC after the frame that's currently in DR
+const SPI_CR1_CRCNEXT: u32 = 1 << 12;
 /// Data frame format
 ///  0: 8-bit data frame
 ///  1: 16-bit data frame
@@ -54,21 +66,206 @@ const SPI_CR1_CPOL: u32 = 1 << 1;
 ///  1: The second clock transition is the first data capture edge
 const SPI_CR1_CPHA: u32 = 1 << 0;
 
+/// RX buffer DMA enable
+const SPI_CR2_RXDMAEN: u32 = 1 << 0;
+/// TX buffer DMA enable
+const SPI_CR2_TXDMAEN: u32 = 1 << 1;
+
+/// TX buffer empty interrupt enable
+const SPI_CR2_TXEIE: u32 = 1 << 7;
+/// RX buffer not empty interrupt enable
+const SPI_CR2_RXNEIE: u32 = 1 << 6;
+/// Error interrupt enable
+const SPI_CR2_ERRIE: u32 = 1 << 5;
+
+/// Offset of DR in the register map (for the DMA)
+const SPI_DR_OFFSET: u32 = 0x0c;
+
+/// Busy communicating (or the TX buffer isn't empty)
+const SPI_SR_BSY: u32 = 1 << 7;
+/// Overrun (a frame arrived before the previous one was read)
+const SPI_SR_OVR: u32 = 1 << 6;
+/// The received CRC didn't match the calculated one (cleared by writing 0)
+const SPI_SR_CRCERR: u32 = 1 << 4;
 /// Transmition buffer empty (can transmit?)
 const SPI_SR_TXE: u32 = 1 << 1;
 /// Reception buffer not empty (is there data to be received?)
 const SPI_SR_RXNE: u32 = 1 << 0;
 
-#[derive(PartialEq)]
+/// What's sent out when only reading
+const DUMMY: u16 = 0xffff;
+/// Where the DMA takes the dummy bytes from
+static DUMMY_BYTE: u8 = DUMMY as u8;
+
+/// A single frame, either 8 or 16 bits wide
+trait Word: Copy {
+  fn from_dr(dr: u32) -> Self;
+  fn to_dr(self) -> u32;
+}
+
+impl Word for u8 {
+  fn from_dr(dr: u32) -> u8 { dr as u8 }
+  fn to_dr(self) -> u32 { self as u32 }
+}
+
+impl Word for u16 {
+  fn from_dr(dr: u32) -> u16 { dr as u16 }
+  fn to_dr(self) -> u32 { self as u32 }
+}
+
+/// Clock polarity and phase, as in the usual SPI mode numbering
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+  /// CPOL = 0, CPHA = 0
+  Mode0,
+  /// CPOL = 0, CPHA = 1
+  Mode1,
+  /// CPOL = 1, CPHA = 0
+  Mode2,
+  /// CPOL = 1, CPHA = 1
+  Mode3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameSize {
+  Bits8,
+  Bits16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitOrder {
+  MsbFirst,
+  LsbFirst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpiConfig {
+  pub mode: Mode,
+  /// The highest SCK frequency (in Hz) that's acceptable, the actual one is
+  /// the closest one that the prescaler can give without exceeding it
+  pub frequency: u32,
+  pub frame_size: FrameSize,
+  pub bit_order: BitOrder,
+  /// The CRC polynomial (eg. 0x07 for CRC-7 with 8-bit frames, 0x1021 for
+  /// CRC-CCITT with 16-bit frames) or None to not use the CRC, the CRC is
+  /// only sent and checked by the *_crc transfers
+  pub crc: Option<u16>,
+}
+
+/// Mode 0, 4MHz, 8-bit frames, MSB first, no CRC
+pub const DEFAULT_CONFIG: SpiConfig = SpiConfig {
+  mode: Mode::Mode0,
+  frequency: 4_000_000,
+  frame_size: FrameSize::Bits8,
+  bit_order: BitOrder::MsbFirst,
+  crc: None,
+};
+
+/// Base address of the registers + the peripheral clock
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct SPI {
   regmap: *mut SPI_register_map,
+  clock: rcc::Clock,
+}
+
+/// Errors of the SPI transactions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+  /// Another transaction is in progress on the bus
+  Busy,
+  /// The CRC that came back doesn't match the data
+  Crc,
+  /// A CRC transfer was requested, but the bus isn't configured with a CRC
+  /// polynomial
+  CrcDisabled,
+  /// A response got lost (something held us up for longer than a frame), the
+  /// transfer stopped there
+  Overrun,
+  /// The DMA failed to move the data, the transfer stopped there
+  Dma,
+}
+
+/// How a device's chip select line is driven
+#[derive(Clone, Copy)]
+pub enum ChipSelect {
+  /// An on-chip pin, active low
+  Pin(gpio::Gpio, u8),
+  /// Anything else (eg. a pin on an IO expander), called with true to select
+  /// the device and with false to release it
+  Custom(fn(bool)),
+}
+
+/// A transfer driven by the TXE and RXNE interrupts
+#[derive(Clone, Copy)]
+struct IrqTransfer {
+  buf: *mut u8,
+  len: usize,
+  sent: usize,
+  received: usize,
+  done: fn(&'static mut [u8], Result<(), Error>),
+}
+
+/// Frames lost in slave mode (counted by the interrupt handler)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlaveStats {
+  /// The frame came in before the previous one was taken out of DR
+  pub overrun: u32,
+  /// There was no room left in the RX buffer (see slave_read)
+  pub dropped: u32,
+}
+
+/// A chip on one of the buses, along with the settings it needs
+#[derive(Clone, Copy)]
+pub struct SpiDevice {
+  bus: SPI,
+  cs: ChipSelect,
+  config: SpiConfig,
 }
 
-pub const SPI1: SPI = SPI { regmap: 0x4001_3000 as *mut SPI_register_map };
+/// Whether there's a transaction in progress on the bus (one per bus)
+static mut bus_taken: [bool; 2] = [false; 2];
+/// What the bus was last configured with (one per bus)
+static mut bus_config: [Option<SpiConfig>; 2] = [None; 2];
+/// The buffer and the callback of the DMA transfer in progress (one per bus)
+static mut dma_transfers: [Option<(*mut u8, usize, fn(&'static mut [u8], Result<(), Error>))>; 2] = [None; 2];
+/// The interrupt-driven transfer in progress (one per bus)
+static mut irq_transfers: [Option<IrqTransfer>; 2] = [None; 2];
+/// How many interrupt-driven transfers were cut short (one per bus)
+static mut irq_overruns: [u32; 2] = [0; 2];
+/// The device selected for the asynchronous transfer in progress (one per bus)
+static mut async_devices: [Option<SpiDevice>; 2] = [None; 2];
+/// What the master sent us in slave mode (one per bus)
+static mut slave_rx: [RingBuf; 2] = [RingBuf::new(), RingBuf::new()];
+/// What will be sent back to the master in slave mode (one per bus)
+static mut slave_tx: [RingBuf; 2] = [RingBuf::new(), RingBuf::new()];
+/// How many frames the current slave transaction had so far (one per bus)
+static mut slave_frames: [usize; 2] = [0; 2];
+/// Called when the master releases NSS (one per bus)
+static mut slave_callbacks: [Option<fn(usize)>; 2] = [None; 2];
+/// What went wrong in slave mode so far (one per bus)
+static mut slave_stats: [SlaveStats; 2] = [SlaveStats { overrun: 0, dropped: 0 }; 2];
+
+pub const SPI1: SPI = SPI { regmap: 0x4001_3000 as *mut SPI_register_map, clock: rcc::Clock::PCLK2 };
+pub const SPI2: SPI = SPI { regmap: 0x4000_3800 as *mut SPI_register_map, clock: rcc::Clock::PCLK1 };
 
 impl SPI {
-  pub fn initialize(self) {
+  /// Enable the clock, set up the pins and configure the SPI as a master,
+  /// returns the actual SCK frequency
+  pub fn initialize(self, config: &SpiConfig) -> u32 {
     if self == SPI1 {
+      rcc::enable(rcc::Periph::apb2_spi1);
+    } else if self == SPI2 {
+      rcc::enable(rcc::Periph::apb1_spi2);
+    }
+
+    self.set_master_pins();
+
+    self.configure(config)
+  }
+
+  /// SCK and MOSI driven by us, MISO by the slaves
+  fn set_master_pins(&self) {
+    if *self == SPI1 {
       // SCK
       gpio::GPIOA.set_pin_mode(5, gpio::PinMode::OutAltPP);
       gpio::GPIOA.set_pin_speed(5, gpio::PinSpeed::Max50MHz);
@@ -77,35 +274,187 @@ impl SPI {
       gpio::GPIOA.set_pin_speed(7, gpio::PinSpeed::Max50MHz);
       // MISO
       gpio::GPIOA.set_pin_mode(6, gpio::PinMode::InFloat);
-      // CS
-      gpio::GPIOC.set_pin_mode(0, gpio::PinMode::OutPP);
-      // Disable the CS line (it's inactive when high)
-      gpio::GPIOC.enable_pin(0);
+    } else {
+      // SCK
+      gpio::GPIOB.set_pin_mode(13, gpio::PinMode::OutAltPP);
+      gpio::GPIOB.set_pin_speed(13, gpio::PinSpeed::Max50MHz);
+      // MOSI
+      gpio::GPIOB.set_pin_mode(15, gpio::PinMode::OutAltPP);
+      gpio::GPIOB.set_pin_speed(15, gpio::PinSpeed::Max50MHz);
+      // MISO
+      gpio::GPIOB.set_pin_mode(14, gpio::PinMode::InFloat);
     }
+  }
+
+  /// Whether the SPI was set up as a master (and can do the transfers)
+  pub fn is_initialized(&self) -> bool {
+    let cr1 = unsafe { (*self.regmap).CR1 };
+
+    cr1 & (SPI_CR1_SPE | SPI_CR1_MSTR) == SPI_CR1_SPE | SPI_CR1_MSTR
+  }
+
+  /// Whether the SPI is enabled (as a master or a slave) and the pin is its
+  /// SCK, MISO or MOSI
+  pub fn owns_pin(&self, port: gpio::Gpio, pin: u8) -> bool {
+    let (bus_port, first) = if *self == SPI1 { (gpio::GPIOA, 5) } else { (gpio::GPIOB, 13) };
+    let enabled = unsafe { (*self.regmap).CR1 & SPI_CR1_SPE != 0 };
+
+    enabled && port == bus_port && pin >= first && pin <= first + 2
+  }
+
+  /// Mark the bus as taken, returns false if it already was
+  fn take(&self) -> bool {
+    let index = self.index();
+
+    nvic::without_interrupts(|| unsafe {
+      let taken = bus_taken[index];
+      bus_taken[index] = true;
+      !taken
+    })
+  }
 
+  fn give_back(&self) {
     unsafe {
-      // Scale the baud rate by 16 => 64MHz / 16 = 4MHz (4Mbps) which
-      // is appropriate for the SPI
-      (*self.regmap).CR1 = 0b011 << 3;
+      bus_taken[self.index()] = false;
+    }
+  }
 
-      // Clock polarity -> 0, clock phase -> 0
-      (*self.regmap).CR1 &= !(SPI_CR1_CPOL | SPI_CR1_CPHA);
+  /// Take the bus and run `f` with it, for whoever drives the chip select
+  /// on their own (or reconfigures the bus)
+  ///
+  /// Fails with Error::Busy like SpiDevice::transaction does.
+  pub fn transaction<F, R>(&self, f: F) -> Result<R, Error> where F: FnOnce(&SPI) -> R {
+    if !self.take() {
+      return Err(Error::Busy);
+    }
 
-      // 8-bits of data in a frame
-      (*self.regmap).CR1 &= !SPI_CR1_DFF;
+    let ret = f(self);
 
-      // MSB goes first
-      (*self.regmap).CR1 &= !SPI_CR1_LSBFIRST;
+    self.give_back();
 
-      // The CS line will be controlled by software
-      (*self.regmap).CR1 |= SPI_CR1_SSM | SPI_CR1_SSI;
+    Ok(ret)
+  }
 
-      // Let's be the master
-      (*self.regmap).CR1 |= SPI_CR1_MSTR;
+  fn index(&self) -> usize {
+    if *self == SPI1 { 0 } else { 1 }
+  }
+
+  fn irq(&self) -> nvic::Irq {
+    if *self == SPI1 { nvic::Irq::SPI1 } else { nvic::Irq::SPI2 }
+  }
+
+  /// The (port, pin) of the hardware NSS line
+  fn nss_pin(&self) -> (gpio::Gpio, u8) {
+    if *self == SPI1 { (gpio::GPIOA, 4) } else { (gpio::GPIOB, 12) }
+  }
+
+  /// CPOL, CPHA, DFF and LSBFIRST for the configuration
+  fn frame_format(config: &SpiConfig) -> u32 {
+    let mut cr1 = 0;
+
+    match config.mode {
+      Mode::Mode0 => (),
+      Mode::Mode1 => cr1 |= SPI_CR1_CPHA,
+      Mode::Mode2 => cr1 |= SPI_CR1_CPOL,
+      Mode::Mode3 => cr1 |= SPI_CR1_CPOL | SPI_CR1_CPHA,
+    }
+
+    if config.frame_size == FrameSize::Bits16 {
+      cr1 |= SPI_CR1_DFF;
+    }
+
+    if config.bit_order == BitOrder::LsbFirst {
+      cr1 |= SPI_CR1_LSBFIRST;
+    }
+
+    cr1
+  }
+
+  /// The BR bits (SCK = PCLK / 2^(BR + 1)) for the highest SCK frequency
+  /// that doesn't exceed the requested one
+  fn baud_rate(pclk: u32, frequency: u32) -> u32 {
+    // Find the smallest divider (2, 4, ..., 256) which doesn't make SCK
+    // faster than requested (or just use the largest one)
+    let mut br = 0;
+    while br < 0b111 && pclk >> (br + 1) > frequency {
+      br += 1;
+    }
+
+    br
+  }
+
+  /// Apply the configuration (can be done at any time, the SPI is disabled
+  /// for a while once it's done with what it was doing), returns the actual
+  /// SCK frequency
+  ///
+  /// A bus in slave mode (see initialize_slave) leaves it and gets its pins
+  /// back.
+  pub fn configure(&self, config: &SpiConfig) -> u32 {
+    let was_slave = unsafe { slave_callbacks[self.index()].is_some() };
+
+    if was_slave {
+      self.stop_slave();
+      self.set_master_pins();
+    }
+
+    let pclk = rcc::get_clock_speed(self.clock);
+    let br = SPI::baud_rate(pclk, config.frequency);
+
+    let mut cr1 = br << 3 | SPI::frame_format(config);
+
+    if config.crc.is_some() {
+      cr1 |= SPI_CR1_CRCEN;
+    }
+
+    // The CS line will be controlled by software
+    cr1 |= SPI_CR1_SSM | SPI_CR1_SSI;
+
+    // Let's be the master
+    cr1 |= SPI_CR1_MSTR;
+
+    unsafe {
+      // Let the ongoing transfer finish, the settings can't be changed
+      // while the SPI is enabled
+      while (*self.regmap).SR & SPI_SR_BSY != 0 {}
+      (*self.regmap).CR1 &= !SPI_CR1_SPE;
+
+      (*self.regmap).CR1 = cr1;
+
+      if let Some(polynomial) = config.crc {
+        (*self.regmap).CRCPR = polynomial as u32;
+      }
 
       // Actually enable the SPI device
       (*self.regmap).CR1 |= SPI_CR1_SPE;
     }
+
+    // The reply that was waiting for the master goes out now, and whatever
+    // comes back isn't a response to anything
+    if was_slave {
+      self.wait_idle();
+
+      unsafe {
+        let _ = (*self.regmap).DR;
+        let _ = (*self.regmap).SR;
+      }
+    }
+
+    unsafe {
+      bus_config[self.index()] = Some(*config);
+    }
+
+    let frequency = pclk >> (br + 1);
+
+    debug!("SPI at {:p} configured, CR1 = 0x{:04x}, SCK = {} Hz", self.regmap, cr1, frequency);
+
+    frequency
+  }
+
+  /// The current SCK frequency
+  pub fn frequency(&self) -> u32 {
+    let br = unsafe { ((*self.regmap).CR1 & SPI_CR1_BR) >> 3 };
+
+    rcc::get_clock_speed(self.clock) >> (br + 1)
   }
 
   pub fn send_recv_byte(&self, byte: u8) -> u8 {
@@ -114,17 +463,1077 @@ impl SPI {
       (*self.regmap).DR = byte as u32;
 
       while (*self.regmap).SR & SPI_SR_RXNE == 0 {}
-      return (*self.regmap).DR as u8;
+      let received = (*self.regmap).DR as u8;
+
+      trace!("sent 0x{:02x}, received 0x{:02x}", byte, received);
+
+      return received;
+    }
+  }
+
+  pub fn send_recv_word(&self, word: u16) -> Result<u16, Error> {
+    let mut buf = [word];
+
+    self.transfer_in_place16(&mut buf)?;
+
+    Ok(buf[0])
+  }
+
+  /// Wait until the last frame is completely out (that's when it's safe to
+  /// release the CS line or reconfigure the SPI)
+  pub fn wait_idle(&self) {
+    unsafe {
+      while (*self.regmap).SR & SPI_SR_TXE == 0 {}
+      while (*self.regmap).SR & SPI_SR_BSY != 0 {}
+    }
+  }
+
+  /// Send the bytes, ignoring whatever comes back
+  pub fn write(&self, data: &[u8]) {
+    self.write_words(data);
+  }
+
+  /// Fill the buffer with what comes back when sending 0xff's
+  pub fn read(&self, buf: &mut [u8]) -> Result<(), Error> {
+    for byte in buf.iter_mut() {
+      *byte = DUMMY as u8;
+    }
+
+    self.transfer_words(buf)
+  }
+
+  /// Send the bytes, replacing each one with the one that came back
+  ///
+  /// Fails with Error::Overrun if a response got lost, the rest of the buffer
+  /// isn't sent then.
+  pub fn transfer_in_place(&self, buf: &mut [u8]) -> Result<(), Error> {
+    self.transfer_words(buf)
+  }
+
+  /// Like write, for the 16-bit frames (see SpiConfig)
+  pub fn write16(&self, data: &[u16]) {
+    self.write_words(data);
+  }
+
+  /// Like read, for the 16-bit frames (see SpiConfig)
+  pub fn read16(&self, buf: &mut [u16]) -> Result<(), Error> {
+    for word in buf.iter_mut() {
+      *word = DUMMY;
+    }
+
+    self.transfer_words(buf)
+  }
+
+  /// Like transfer_in_place, for the 16-bit frames (see SpiConfig)
+  pub fn transfer_in_place16(&self, buf: &mut [u16]) -> Result<(), Error> {
+    self.transfer_words(buf)
+  }
+
+  fn write_words<W: Word>(&self, data: &[W]) {
+    unsafe {
+      for &word in data {
+        while (*self.regmap).SR & SPI_SR_TXE == 0 {}
+        (*self.regmap).DR = word.to_dr();
+      }
+    }
+
+    self.wait_idle();
+
+    // Nobody read what came back, so there's surely an overrun - reading DR
+    // and then SR clears it
+    unsafe {
+      let _ = (*self.regmap).DR;
+      let _ = (*self.regmap).SR;
+    }
+  }
+
+  fn transfer_words<W: Word>(&self, buf: &mut [W]) -> Result<(), Error> {
+    let len = buf.len();
+    let mut sent = 0;
+    let mut received = 0;
+    let mut result = Ok(());
+
+    unsafe {
+      while received < len {
+        let sr = (*self.regmap).SR;
+
+        // A response got lost (something must've held us up for longer than
+        // a frame), there's no way to tell which one so just give up
+        if sr & SPI_SR_OVR != 0 {
+          let _ = (*self.regmap).DR;
+          let _ = (*self.regmap).SR;
+
+          result = Err(Error::Overrun);
+          break;
+        }
+
+        // Keep the TX buffer full while the previous frame is still being
+        // shifted out, but never get more than two frames ahead of what was
+        // read back, as the third one would overrun the first one's response
+        if sent < len && sent - received < 2 && sr & SPI_SR_TXE != 0 {
+          (*self.regmap).DR = buf[sent].to_dr();
+          sent += 1;
+        }
+
+        if sr & SPI_SR_RXNE != 0 {
+          buf[received] = W::from_dr((*self.regmap).DR);
+          received += 1;
+        }
+      }
+    }
+
+    self.wait_idle();
+
+    result
+  }
+}
+
+// CRC transfers
+impl SPI {
+  /// Whether the bus was configured with a CRC polynomial
+  pub fn is_crc_enabled(&self) -> bool {
+    unsafe { (*self.regmap).CR1 & SPI_CR1_CRCEN != 0 }
+  }
+
+  /// Send the bytes followed by their CRC, ignoring whatever comes back
+  ///
+  /// Nothing is sent at all (not even the CRC) if there's no data.
+  pub fn write_crc(&self, data: &[u8]) -> Result<(), Error> {
+    if !self.is_crc_enabled() {
+      return Err(Error::CrcDisabled);
     }
+
+    // CRCNEXT only takes effect after a frame, the CRC would never go out
+    if data.is_empty() {
+      return Ok(());
+    }
+
+    self.reset_crc();
+
+    unsafe {
+      for &byte in data {
+        while (*self.regmap).SR & SPI_SR_TXE == 0 {}
+        (*self.regmap).DR = byte as u32;
+      }
+
+      (*self.regmap).CR1 |= SPI_CR1_CRCNEXT;
+    }
+
+    self.wait_idle();
+
+    // Nobody read what came back (nor cares about the CRC of it)
+    unsafe {
+      let _ = (*self.regmap).DR;
+      let _ = (*self.regmap).SR;
+      (*self.regmap).CR1 &= !SPI_CR1_CRCNEXT;
+      (*self.regmap).SR &= !SPI_SR_CRCERR;
+    }
+
+    Ok(())
+  }
+
+  /// Like read, but the data has to be followed by a matching CRC
+  pub fn read_crc(&self, buf: &mut [u8]) -> Result<(), Error> {
+    for byte in buf.iter_mut() {
+      *byte = DUMMY as u8;
+    }
+
+    self.transfer_words_crc(buf)
+  }
+
+  /// Like transfer_in_place, but the data is followed by its CRC (in both
+  /// directions), fails if the one that came back doesn't match
+  pub fn transfer_in_place_crc(&self, buf: &mut [u8]) -> Result<(), Error> {
+    self.transfer_words_crc(buf)
+  }
+
+  /// Like transfer_in_place_crc, for the 16-bit frames (see SpiConfig)
+  pub fn transfer_in_place_crc16(&self, buf: &mut [u16]) -> Result<(), Error> {
+    self.transfer_words_crc(buf)
   }
 
-  //pub fn recv_byte(&self) -> u8 {
-    //unsafe {
-      //while (*self.regmap).SR & SPI_SR_RXNE == 0 {}
+  /// Start the calculation over (it only gets reset by disabling it, and the
+  /// SPI has to be disabled for that)
+  fn reset_crc(&self) {
+    self.wait_idle();
+
+    unsafe {
+      (*self.regmap).CR1 &= !SPI_CR1_SPE;
+      (*self.regmap).CR1 &= !SPI_CR1_CRCEN;
+      (*self.regmap).CR1 |= SPI_CR1_CRCEN;
+      (*self.regmap).SR &= !SPI_SR_CRCERR;
+      (*self.regmap).CR1 |= SPI_CR1_SPE;
+    }
+  }
+
+  fn transfer_words_crc<W: Word>(&self, buf: &mut [W]) -> Result<(), Error> {
+    if !self.is_crc_enabled() {
+      return Err(Error::CrcDisabled);
+    }
+
+    // Same as in write_crc, the CRC would never come back
+    if buf.is_empty() {
+      return Ok(());
+    }
+
+    self.reset_crc();
 
-      //return (*self.regmap).DR as u8;
-    //}
-  //}
+    let len = buf.len();
+    let mut sent = 0;
+    let mut received = 0;
+    let mut overrun = false;
+
+    unsafe {
+      while received < len {
+        let sr = (*self.regmap).SR;
+
+        if sr & SPI_SR_OVR != 0 {
+          let _ = (*self.regmap).DR;
+          let _ = (*self.regmap).SR;
+
+          overrun = true;
+          break;
+        }
+
+        if sent < len && sent - received < 2 && sr & SPI_SR_TXE != 0 {
+          (*self.regmap).DR = buf[sent].to_dr();
+          sent += 1;
+
+          // Has to be set before the last frame is done being shifted out
+          if sent == len {
+            (*self.regmap).CR1 |= SPI_CR1_CRCNEXT;
+          }
+        }
+
+        if sr & SPI_SR_RXNE != 0 {
+          buf[received] = W::from_dr((*self.regmap).DR);
+          received += 1;
+        }
+      }
+
+      // The CRC that came back ends up in DR too, CRCERR is only valid once
+      // it did
+      if received == len {
+        while (*self.regmap).SR & SPI_SR_RXNE == 0 {}
+        let _ = (*self.regmap).DR;
+      }
+    }
+
+    self.wait_idle();
+
+    unsafe {
+      (*self.regmap).CR1 &= !SPI_CR1_CRCNEXT;
+
+      // The CRC is of no use without all the data
+      if overrun {
+        (*self.regmap).SR &= !SPI_SR_CRCERR;
+        return Err(Error::Overrun);
+      }
+
+      if (*self.regmap).SR & SPI_SR_CRCERR != 0 {
+        (*self.regmap).SR &= !SPI_SR_CRCERR;
+
+        let expected = (*self.regmap).RXCRCR;
+        warn!("CRC mismatch, expected 0x{:04x}", expected);
+        return Err(Error::Crc);
+      }
+    }
+
+    Ok(())
+  }
+}
+
+// DMA transfers
+impl SPI {
+  /// The (RX, TX) channels serving the bus
+  fn dma_channels(&self) -> (dma::Channel, dma::Channel) {
+    if *self == SPI1 {
+      (dma::CHANNEL2, dma::CHANNEL3)
+    } else {
+      (dma::CHANNEL4, dma::CHANNEL5)
+    }
+  }
+
+  fn claim_dma(&self) -> bool {
+    let (rx, tx) = self.dma_channels();
+
+    if !rx.claim() {
+      return false;
+    }
+
+    if !tx.claim() {
+      rx.release();
+      return false;
+    }
+
+    true
+  }
+
+  fn release_dma(&self) {
+    let (rx, tx) = self.dma_channels();
+
+    unsafe {
+      (*self.regmap).CR2 &= !(SPI_CR2_RXDMAEN | SPI_CR2_TXDMAEN);
+    }
+
+    rx.set_callback(None);
+    tx.set_callback(None);
+    rx.release();
+    tx.release();
+  }
+
+  /// The DMA moves the frames as bytes, so it can't be used with the 16-bit
+  /// ones
+  fn can_use_dma(&self, len: usize) -> bool {
+    let dff = unsafe { (*self.regmap).CR1 & SPI_CR1_DFF };
+
+    len != 0 && len <= 0xffff && dff == 0
+  }
+
+  /// Receive into `rx` (if given) while sending `len` bytes from `tx` (or the
+  /// first byte there over and over if `tx_increment` is false), the frames
+  /// have to be 8-bit (see can_use_dma)
+  fn start_dma(&self, rx: Option<u32>, tx: u32, tx_increment: bool, len: u16) {
+    let (rx_channel, tx_channel) = self.dma_channels();
+    let dr = self.regmap as u32 + SPI_DR_OFFSET;
+
+    unsafe {
+      // Whatever is left in DR would be the first thing the RX channel picks
+      // up (and an overrun would stall it)
+      let _ = (*self.regmap).DR;
+      let _ = (*self.regmap).SR;
+
+      // RX has to go first, so that it's ready for the very first frame
+      if let Some(memory) = rx {
+        rx_channel.start(&dma::Transfer {
+          peripheral: dr,
+          memory: memory,
+          count: len,
+          direction: dma::Direction::PeripheralToMemory,
+          memory_increment: true,
+          size: dma::Size::Bits8,
+          circular: false,
+          priority: dma::Priority::VeryHigh,
+        });
+
+        (*self.regmap).CR2 |= SPI_CR2_RXDMAEN;
+      }
+
+      tx_channel.start(&dma::Transfer {
+        peripheral: dr,
+        memory: tx,
+        count: len,
+        direction: dma::Direction::MemoryToPeripheral,
+        memory_increment: tx_increment,
+        size: dma::Size::Bits8,
+        circular: false,
+        priority: dma::Priority::Medium,
+      });
+
+      (*self.regmap).CR2 |= SPI_CR2_TXDMAEN;
+    }
+  }
+
+  /// Wait for the channel, then for the SPI itself
+  fn wait_for_dma(&self, channel: dma::Channel) -> Result<(), Error> {
+    let (rx, tx) = self.dma_channels();
+
+    // An error on either channel stops the whole transfer
+    while !channel.is_complete() && !rx.has_failed() && !tx.has_failed() {}
+
+    let failed = rx.has_failed() || tx.has_failed();
+
+    self.wait_idle();
+
+    if failed {
+      // A frame may still be waiting in DR
+      unsafe {
+        let _ = (*self.regmap).DR;
+        let _ = (*self.regmap).SR;
+      }
+
+      return Err(Error::Dma);
+    }
+
+    Ok(())
+  }
+
+  /// Like transfer_in_place, but the data is moved by the DMA
+  ///
+  /// Falls back to the polled transfer if the bus' DMA channels are taken,
+  /// the buffer is longer than the DMA can handle or the bus uses 16-bit
+  /// frames.
+  pub fn transfer_in_place_dma(&self, buf: &mut [u8]) -> Result<(), Error> {
+    if !self.can_use_dma(buf.len()) || !self.claim_dma() {
+      return self.transfer_in_place(buf);
+    }
+
+    let memory = buf.as_mut_ptr() as u32;
+
+    self.start_dma(Some(memory), memory, true, buf.len() as u16);
+    let result = self.wait_for_dma(self.dma_channels().0);
+    self.release_dma();
+
+    result
+  }
+
+  /// Like write, but the data is moved by the DMA (with the same fallback as
+  /// transfer_in_place_dma)
+  pub fn write_dma(&self, data: &[u8]) -> Result<(), Error> {
+    if !self.can_use_dma(data.len()) || !self.claim_dma() {
+      self.write(data);
+      return Ok(());
+    }
+
+    self.start_dma(None, data.as_ptr() as u32, true, data.len() as u16);
+    let result = self.wait_for_dma(self.dma_channels().1);
+
+    // Nobody read what came back
+    unsafe {
+      let _ = (*self.regmap).DR;
+      let _ = (*self.regmap).SR;
+    }
+
+    self.release_dma();
+
+    result
+  }
+
+  /// Like read, but the data is moved by the DMA (with the same fallback as
+  /// transfer_in_place_dma)
+  pub fn read_dma(&self, buf: &mut [u8]) -> Result<(), Error> {
+    if !self.can_use_dma(buf.len()) || !self.claim_dma() {
+      return self.read(buf);
+    }
+
+    self.start_dma(Some(buf.as_mut_ptr() as u32), &DUMMY_BYTE as *const u8 as u32, false, buf.len() as u16);
+    let result = self.wait_for_dma(self.dma_channels().0);
+    self.release_dma();
+
+    result
+  }
+
+  /// Start a DMA transfer_in_place and return right away, `done` is called
+  /// (from the interrupt handler) with the buffer and how it went once
+  /// everything came back
+  ///
+  /// The bus stays taken until then (see SpiDevice::transaction), this fails
+  /// with Error::Busy if there's a transaction in progress on it already. If
+  /// the DMA can't be used (see transfer_in_place_dma), the transfer is
+  /// driven by the interrupts instead (see transfer_in_place_irq), and if the
+  /// buffer is empty or too long for the DMA, it's done right here (polled)
+  /// and `done` is called before this returns. If the DMA fails, `done` gets
+  /// the buffer as it is, along with Error::Dma.
+  pub fn transfer_in_place_async(&self, buf: &'static mut [u8], done: fn(&'static mut [u8], Result<(), Error>)) -> Result<(), Error> {
+    if !self.take() {
+      return Err(Error::Busy);
+    }
+
+    self.start_async(buf, done);
+
+    Ok(())
+  }
+
+  /// transfer_in_place_async on a bus that's already taken
+  fn start_async(&self, buf: &'static mut [u8], done: fn(&'static mut [u8], Result<(), Error>)) {
+    if buf.is_empty() || buf.len() > 0xffff {
+      let result = self.transfer_in_place(buf);
+      self.end_async();
+      done(buf, result);
+      return;
+    }
+
+    if !self.can_use_dma(buf.len()) || !self.claim_dma() {
+      self.start_irq(buf, done);
+      return;
+    }
+
+    let (rx, tx) = self.dma_channels();
+    let memory = buf.as_mut_ptr();
+    let len = buf.len();
+
+    unsafe {
+      dma_transfers[self.index()] = Some((memory, len, done));
+    }
+
+    if *self == SPI1 {
+      rx.set_callback(Some(spi1_dma_event));
+      tx.set_callback(Some(spi1_dma_tx_event));
+    } else {
+      rx.set_callback(Some(spi2_dma_event));
+      tx.set_callback(Some(spi2_dma_tx_event));
+    }
+
+    self.start_dma(Some(memory as u32), memory as u32, true, len as u16);
+  }
+
+  /// Deselect the device the asynchronous transfer was for (if any) and give
+  /// the bus back
+  fn end_async(&self) {
+    let device = unsafe { async_devices[self.index()].take() };
+
+    if let Some(device) = device {
+      device.select(false);
+    }
+
+    self.give_back();
+  }
+
+  /// Whether the transfer started with transfer_in_place_async is still going
+  /// (whether it's the DMA or the interrupts that are doing it)
+  pub fn is_dma_busy(&self) -> bool {
+    self.is_irq_busy() || unsafe { dma_transfers[self.index()].is_some() }
+  }
+
+  /// Block until the transfer started with transfer_in_place_async is done
+  pub fn wait_dma(&self) {
+    while self.is_dma_busy() {}
+  }
+
+  /// Called for the events of the RX channel, and for the errors of the TX
+  /// one (the RX channel would never complete after those)
+  fn finish_dma(&self, event: dma::Event) {
+    let result = match event {
+      dma::Event::HalfComplete => return,
+      dma::Event::Error => Err(Error::Dma),
+      dma::Event::Complete => Ok(()),
+    };
+
+    let (memory, len, done) = match unsafe { dma_transfers[self.index()].take() } {
+      Some(transfer) => transfer,
+      // Both channels failed, the first error already finished it
+      None => return,
+    };
+
+    // Stops both channels
+    self.release_dma();
+    self.wait_idle();
+
+    // After an error, a frame may still be waiting in DR
+    unsafe {
+      let _ = (*self.regmap).DR;
+      let _ = (*self.regmap).SR;
+    }
+
+    self.end_async();
+
+    done(unsafe { slice::from_raw_parts_mut(memory, len) }, result);
+  }
+}
+
+fn spi1_dma_event(event: dma::Event) {
+  SPI1.finish_dma(event);
+}
+
+fn spi2_dma_event(event: dma::Event) {
+  SPI2.finish_dma(event);
+}
+
+fn spi1_dma_tx_event(event: dma::Event) {
+  if event == dma::Event::Error {
+    SPI1.finish_dma(event);
+  }
+}
+
+fn spi2_dma_tx_event(event: dma::Event) {
+  if event == dma::Event::Error {
+    SPI2.finish_dma(event);
+  }
+}
+
+// Interrupt-driven transfers
+impl SPI {
+  /// Start a transfer_in_place driven by the TXE and RXNE interrupts and
+  /// return right away, `done` is called (from the interrupt handler) with the
+  /// buffer and how it went once everything came back
+  ///
+  /// The bus stays taken until then (see SpiDevice::transaction), this fails
+  /// with Error::Busy if there's a transaction in progress on it already. If
+  /// a response gets lost, the transfer stops there and `done` gets the
+  /// buffer as it is, along with Error::Overrun.
+  pub fn transfer_in_place_irq(&self, buf: &'static mut [u8], done: fn(&'static mut [u8], Result<(), Error>)) -> Result<(), Error> {
+    if !self.take() {
+      return Err(Error::Busy);
+    }
+
+    self.start_irq(buf, done);
+
+    Ok(())
+  }
+
+  /// transfer_in_place_irq on a bus that's already taken
+  fn start_irq(&self, buf: &'static mut [u8], done: fn(&'static mut [u8], Result<(), Error>)) {
+    if buf.is_empty() {
+      self.end_async();
+      done(buf, Ok(()));
+      return;
+    }
+
+    let transfer = IrqTransfer { buf: buf.as_mut_ptr(), len: buf.len(), sent: 0, received: 0, done: done };
+
+    unsafe {
+      irq_transfers[self.index()] = Some(transfer);
+
+      // Whatever is left in DR would be taken for the first response
+      let _ = (*self.regmap).DR;
+      let _ = (*self.regmap).SR;
+
+      // TXE is set, so the interrupt fires right away and sends the first
+      // frame
+      (*self.regmap).CR2 |= SPI_CR2_TXEIE | SPI_CR2_RXNEIE | SPI_CR2_ERRIE;
+    }
+
+    nvic::enable_irq(self.irq());
+  }
+
+  /// Whether the transfer started with transfer_in_place_irq is still going
+  pub fn is_irq_busy(&self) -> bool {
+    unsafe { irq_transfers[self.index()].is_some() }
+  }
+
+  /// Block until the transfer started with transfer_in_place_irq is done
+  pub fn wait_irq(&self) {
+    while self.is_irq_busy() {}
+  }
+
+  /// How many of the interrupt-driven transfers were cut short because a
+  /// response got lost (for the statistics, `done` is told about each one)
+  pub fn irq_overruns(&self) -> u32 {
+    unsafe { ptr::read_volatile(&irq_overruns[self.index()]) }
+  }
+
+  fn handle_transfer_irq(&self) {
+    let index = self.index();
+
+    let mut transfer = match unsafe { irq_transfers[index] } {
+      Some(transfer) => transfer,
+      None => {
+        unsafe {
+          (*self.regmap).CR2 &= !(SPI_CR2_TXEIE | SPI_CR2_RXNEIE | SPI_CR2_ERRIE);
+        }
+        return;
+      },
+    };
+
+    let mut result = Ok(());
+
+    let finished = unsafe {
+      let sr = (*self.regmap).SR;
+
+      if sr & SPI_SR_OVR != 0 {
+        let _ = (*self.regmap).DR;
+        let _ = (*self.regmap).SR;
+
+        irq_overruns[index] += 1;
+        result = Err(Error::Overrun);
+        true
+      } else {
+        if sr & SPI_SR_RXNE != 0 && transfer.received < transfer.len {
+          *transfer.buf.offset(transfer.received as isize) = (*self.regmap).DR as u8;
+          transfer.received += 1;
+        }
+
+        // The same rule as in the polled transfers: never get more than two
+        // frames ahead of what was read back
+        if sr & SPI_SR_TXE != 0 && transfer.sent < transfer.len && transfer.sent - transfer.received < 2 {
+          (*self.regmap).DR = *transfer.buf.offset(transfer.sent as isize) as u32;
+          transfer.sent += 1;
+        }
+
+        // TXE stays set, so stop listening to it while there's nothing that
+        // can be sent (until another frame comes back)
+        if transfer.sent < transfer.len && transfer.sent - transfer.received < 2 {
+          (*self.regmap).CR2 |= SPI_CR2_TXEIE;
+        } else {
+          (*self.regmap).CR2 &= !SPI_CR2_TXEIE;
+        }
+
+        transfer.received == transfer.len
+      }
+    };
+
+    if !finished {
+      unsafe {
+        irq_transfers[index] = Some(transfer);
+      }
+      return;
+    }
+
+    unsafe {
+      (*self.regmap).CR2 &= !(SPI_CR2_TXEIE | SPI_CR2_RXNEIE | SPI_CR2_ERRIE);
+    }
+
+    self.wait_idle();
+
+    unsafe {
+      irq_transfers[index] = None;
+    }
+
+    self.end_async();
+
+    (transfer.done)(unsafe { slice::from_raw_parts_mut(transfer.buf, transfer.len) }, result);
+  }
+}
+
+// Slave mode
+impl SPI {
+  /// Enable the clock, set up the pins and make the SPI a slave to whoever
+  /// drives the hardware NSS line (PA4 for SPI1, PB12 for SPI2)
+  ///
+  /// Everything that comes in ends up in a buffer (see slave_read), and what's
+  /// sent back is taken from another one (see slave_write), 0xff's go out
+  /// when it's empty. `on_end` is called (from the interrupt handler) with the
+  /// number of frames once the master releases NSS.
+  ///
+  /// Only 8-bit frames are supported (the frame size from the configuration
+  /// is ignored) and the frequency is whatever the master drives SCK at. The
+  /// reply to a frame is loaded as soon as the frame comes in, so the master
+  /// has to leave a bit of time between the frames.
+  pub fn initialize_slave(self, config: &SpiConfig, on_end: fn(usize)) {
+    let (nss_port, nss_pin) = self.nss_pin();
+
+    if self == SPI1 {
+      rcc::enable(rcc::Periph::apb2_spi1);
+
+      // SCK, MOSI
+      gpio::GPIOA.set_pin_mode(5, gpio::PinMode::InFloat);
+      gpio::GPIOA.set_pin_mode(7, gpio::PinMode::InFloat);
+      // MISO
+      gpio::GPIOA.set_pin_mode(6, gpio::PinMode::OutAltPP);
+      gpio::GPIOA.set_pin_speed(6, gpio::PinSpeed::Max50MHz);
+    } else if self == SPI2 {
+      rcc::enable(rcc::Periph::apb1_spi2);
+
+      // SCK, MOSI
+      gpio::GPIOB.set_pin_mode(13, gpio::PinMode::InFloat);
+      gpio::GPIOB.set_pin_mode(15, gpio::PinMode::InFloat);
+      // MISO
+      gpio::GPIOB.set_pin_mode(14, gpio::PinMode::OutAltPP);
+      gpio::GPIOB.set_pin_speed(14, gpio::PinSpeed::Max50MHz);
+    }
+
+    nss_port.set_pin_mode(nss_pin, gpio::PinMode::InFloat);
+
+    let index = self.index();
+    let cr1 = SPI::frame_format(config) & !SPI_CR1_DFF;
+
+    unsafe {
+      (*self.regmap).CR1 &= !SPI_CR1_SPE;
+      (*self.regmap).CR1 = cr1;
+
+      slave_frames[index] = 0;
+      slave_stats[index] = SlaveStats { overrun: 0, dropped: 0 };
+      slave_callbacks[index] = Some(on_end);
+      // The master mode settings are gone
+      bus_config[index] = None;
+
+      // Whatever's in the TX buffer goes out with the very first frame
+      (*self.regmap).DR = match slave_tx[index].pop() {
+        Some(byte) => byte as u32,
+        None => DUMMY as u32,
+      };
+
+      (*self.regmap).CR2 |= SPI_CR2_RXNEIE | SPI_CR2_ERRIE;
+    }
+
+    nvic::enable_irq(self.irq());
+    exti::listen(nss_port, nss_pin, exti::Edge::Rising, nss_released);
+
+    unsafe {
+      (*self.regmap).CR1 |= SPI_CR1_SPE;
+    }
+
+    debug!("SPI at {:p} configured as a slave, CR1 = 0x{:04x}", self.regmap, cr1);
+  }
+
+  /// Leave the slave mode (if the SPI was in it)
+  fn stop_slave(&self) {
+    let index = self.index();
+
+    if unsafe { slave_callbacks[index].is_none() } {
+      return;
+    }
+
+    exti::unlisten(self.nss_pin().1);
+    nvic::disable_irq(self.irq());
+
+    unsafe {
+      (*self.regmap).CR2 &= !(SPI_CR2_RXNEIE | SPI_CR2_ERRIE);
+      slave_callbacks[index] = None;
+    }
+  }
+
+  /// Queue the bytes for the master to read, returns how many fit in
+  pub fn slave_write(&self, data: &[u8]) -> usize {
+    let tx = unsafe { &mut slave_tx[self.index()] };
+    let mut written = 0;
+
+    for &byte in data {
+      if !tx.push(byte) {
+        break;
+      }
+
+      written += 1;
+    }
+
+    written
+  }
+
+  /// Take what the master sent out of the buffer, returns how many bytes
+  /// were there
+  pub fn slave_read(&self, buf: &mut [u8]) -> usize {
+    let rx = unsafe { &mut slave_rx[self.index()] };
+    let mut read = 0;
+
+    for byte in buf.iter_mut() {
+      match rx.pop() {
+        Some(received) => *byte = received,
+        None => break,
+      }
+
+      read += 1;
+    }
+
+    read
+  }
+
+  /// How many frames were lost in slave mode since the bus was initialized
+  /// as a slave
+  pub fn slave_stats(&self) -> SlaveStats {
+    nvic::without_interrupts(|| unsafe { slave_stats[self.index()] })
+  }
+
+  fn handle_slave_irq(&self) {
+    let index = self.index();
+
+    unsafe {
+      let sr = (*self.regmap).SR;
+
+      if sr & SPI_SR_OVR != 0 {
+        // Reading DR and then SR clears it (the frame is lost)
+        let _ = (*self.regmap).DR;
+        let _ = (*self.regmap).SR;
+
+        slave_stats[index].overrun += 1;
+        return;
+      }
+
+      if sr & SPI_SR_RXNE != 0 {
+        let byte = (*self.regmap).DR as u8;
+
+        // The frame's still counted even if there's no room for it
+        if !slave_rx[index].push(byte) {
+          slave_stats[index].dropped += 1;
+        }
+
+        slave_frames[index] += 1;
+
+        (*self.regmap).DR = match slave_tx[index].pop() {
+          Some(byte) => byte as u32,
+          None => DUMMY as u32,
+        };
+      }
+    }
+  }
+
+  fn end_slave_transaction(&self) {
+    let index = self.index();
+
+    let frames = nvic::without_interrupts(|| unsafe {
+      let frames = slave_frames[index];
+      slave_frames[index] = 0;
+      frames
+    });
+
+    match unsafe { slave_callbacks[index] } {
+      Some(on_end) => on_end(frames),
+      None => (),
+    }
+  }
+}
+
+/// The EXTI handler of both buses' NSS lines
+fn nss_released(pin: u8) {
+  if pin == SPI1.nss_pin().1 {
+    SPI1.end_slave_transaction();
+  } else {
+    SPI2.end_slave_transaction();
+  }
+}
+
+impl SPI {
+  /// Both the slave mode and the interrupt-driven transfers use the same
+  /// interrupt
+  fn handle_irq(&self) {
+    if unsafe { slave_callbacks[self.index()].is_some() } {
+      self.handle_slave_irq();
+    } else {
+      self.handle_transfer_irq();
+    }
+  }
+}
+
+pub extern "C" fn spi1_irq_handler() { SPI1.handle_irq(); }
+pub extern "C" fn spi2_irq_handler() { SPI2.handle_irq(); }
+
+impl SpiDevice {
+  pub const fn new(bus: SPI, cs: ChipSelect, config: SpiConfig) -> SpiDevice {
+    SpiDevice { bus: bus, cs: cs, config: config }
+  }
+
+  /// Set up the chip select line (and leave the device deselected), the bus
+  /// itself has to be initialized separately
+  pub fn initialize(&self) {
+    match self.cs {
+      ChipSelect::Pin(port, pin) => {
+        port.enable_pin(pin);
+        port.set_pin_mode(pin, gpio::PinMode::OutPP);
+      },
+      ChipSelect::Custom(cs) => cs(false),
+    }
+  }
+
+  pub fn bus(&self) -> SPI {
+    self.bus
+  }
+
+  fn select(&self, selected: bool) {
+    match self.cs {
+      ChipSelect::Pin(port, pin) => {
+        if selected {
+          port.disable_pin(pin);
+        } else {
+          port.enable_pin(pin);
+        }
+      },
+      ChipSelect::Custom(cs) => cs(selected),
+    }
+  }
+
+  /// Take the bus, reconfigure it for this device if needed and run `f` with
+  /// the device selected
+  ///
+  /// Fails right away if there's already a transaction in progress on the
+  /// bus (eg. this was called from an interrupt handler which interrupted
+  /// one), as waiting for it could never end.
+  pub fn transaction<F, R>(&self, f: F) -> Result<R, Error> where F: FnOnce(&SPI) -> R {
+    self.bus.transaction(|bus| {
+      if unsafe { bus_config[bus.index()] } != Some(self.config) {
+        bus.configure(&self.config);
+      }
+
+      self.select(true);
+      let ret = f(bus);
+      // Don't cut off the last frame
+      bus.wait_idle();
+      self.select(false);
+
+      ret
+    })
+  }
+
+  pub fn write(&self, data: &[u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.write(data))
+  }
+
+  pub fn read(&self, buf: &mut [u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.read(buf)).and_then(|result| result)
+  }
+
+  pub fn transfer_in_place(&self, buf: &mut [u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.transfer_in_place(buf)).and_then(|result| result)
+  }
+
+  pub fn write_crc(&self, data: &[u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.write_crc(data)).and_then(|result| result)
+  }
+
+  pub fn read_crc(&self, buf: &mut [u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.read_crc(buf)).and_then(|result| result)
+  }
+
+  pub fn transfer_in_place_crc(&self, buf: &mut [u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.transfer_in_place_crc(buf)).and_then(|result| result)
+  }
+
+  pub fn write_dma(&self, data: &[u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.write_dma(data)).and_then(|result| result)
+  }
+
+  pub fn read_dma(&self, buf: &mut [u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.read_dma(buf)).and_then(|result| result)
+  }
+
+  pub fn transfer_in_place_dma(&self, buf: &mut [u8]) -> Result<(), Error> {
+    self.transaction(|bus| bus.transfer_in_place_dma(buf)).and_then(|result| result)
+  }
+
+  /// Like SPI::transfer_in_place_async, with the device selected (and the
+  /// bus taken) until `done` is called
+  pub fn transfer_in_place_async(&self, buf: &'static mut [u8], done: fn(&'static mut [u8], Result<(), Error>)) -> Result<(), Error> {
+    let index = self.bus.index();
+
+    if !self.bus.take() {
+      return Err(Error::Busy);
+    }
+
+    if unsafe { bus_config[index] } != Some(self.config) {
+      self.bus.configure(&self.config);
+    }
+
+    unsafe {
+      async_devices[index] = Some(*self);
+    }
+
+    self.select(true);
+    self.bus.start_async(buf, done);
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn picks_the_fastest_allowed_sck() {
+    // Exact matches
+    assert_eq!(SPI::baud_rate(72_000_000, 36_000_000), 0);
+    assert_eq!(SPI::baud_rate(72_000_000, 18_000_000), 1);
+    assert_eq!(SPI::baud_rate(36_000_000, 281_250), 6);
+
+    // In between - round down to the slower one
+    assert_eq!(SPI::baud_rate(72_000_000, 4_000_000), 4);
+    assert_eq!(SPI::baud_rate(72_000_000, 17_999_999), 2);
+
+    // Faster than PCLK / 2 can go
+    assert_eq!(SPI::baud_rate(72_000_000, 100_000_000), 0);
+  }
+
+  #[test]
+  fn stops_at_the_largest_divider() {
+    assert_eq!(SPI::baud_rate(72_000_000, 1_000), 0b111);
+    assert_eq!(SPI::baud_rate(72_000_000, 0), 0b111);
+  }
+
+  #[test]
+  fn frame_format_bits() {
+    assert_eq!(SPI::frame_format(&DEFAULT_CONFIG), 0);
+
+    let config = SpiConfig {
+      mode: Mode::Mode3,
+      frame_size: FrameSize::Bits16,
+      bit_order: BitOrder::LsbFirst,
+      .. DEFAULT_CONFIG
+    };
+
+    assert_eq!(SPI::frame_format(&config), SPI_CR1_CPOL | SPI_CR1_CPHA | SPI_CR1_DFF | SPI_CR1_LSBFIRST);
+
+    let config = SpiConfig { mode: Mode::Mode1, .. DEFAULT_CONFIG };
+
+    assert_eq!(SPI::frame_format(&config), SPI_CR1_CPHA);
+  }
 }
 
 /*
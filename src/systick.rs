@@ -0,0 +1,64 @@
+//
+// systick.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:09:47 +0000 (UTC)
+//
+
+use core::ptr;
+
+use mmio;
+use rcc;
+
+/// SysTick Control and Status Register
+const SYST_CSR: u32 = 0xE000_E010;
+/// Counter enable
+const SYST_CSR_ENABLE: u32 = 1 << 0;
+/// Raise the SysTick exception when the counter reaches zero
+const SYST_CSR_TICKINT: u32 = 1 << 1;
+/// Clock source
+///  0: HCLK / 8
+///  1: HCLK
+const SYST_CSR_CLKSOURCE: u32 = 1 << 2;
+/// SysTick Reload Value Register (24 bits)
+const SYST_RVR: u32 = 0xE000_E014;
+/// SysTick Current Value Register
+const SYST_CVR: u32 = 0xE000_E018;
+
+/// Milliseconds since initialize() was called
+static mut ticks: u32 = 0;
+
+/// Make the SysTick fire every millisecond
+pub fn initialize() {
+  let reload = rcc::get_clock_speed(rcc::Clock::HCLK) / 1000 - 1;
+
+  mmio::write(SYST_RVR, reload);
+  mmio::write(SYST_CVR, 0);
+  mmio::write(SYST_CSR, SYST_CSR_CLKSOURCE | SYST_CSR_TICKINT | SYST_CSR_ENABLE);
+}
+
+pub fn is_running() -> bool {
+  mmio::read(SYST_CSR) & SYST_CSR_ENABLE != 0
+}
+
+/// Milliseconds since initialize() was called (wraps around after ~49 days)
+pub fn millis() -> u32 {
+  unsafe { ptr::read_volatile(&ticks) }
+}
+
+pub fn delay_ms(ms: u32) {
+  let start = millis();
+
+  while millis().wrapping_sub(start) < ms {}
+}
+
+pub extern "C" fn systick_handler() {
+  unsafe {
+    ptr::write_volatile(&mut ticks, ticks.wrapping_add(1));
+  }
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
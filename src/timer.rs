@@ -0,0 +1,745 @@
+//
+// timer.rs
+// Copyright (C) 2026 agent <agent@local>
+// Distributed under terms of the BSD (2-clause) license.
+//
+// Created on: 19 Oct 2026 06:27:39 +0000 (UTC)
+//
+
+use rcc;
+use nvic;
+use gpio;
+
+#[repr(packed)]
+struct TIM_register_map {
+  CR1: u32,
+  CR2: u32,
+  SMCR: u32,
+  DIER: u32,
+  SR: u32,
+  EGR: u32,
+  CCMR1: u32,
+  CCMR2: u32,
+  CCER: u32,
+  CNT: u32,
+  PSC: u32,
+  ARR: u32,
+  /// TIM1 only
+  RCR: u32,
+  CCR1: u32,
+  CCR2: u32,
+  CCR3: u32,
+  CCR4: u32,
+  /// TIM1 only
+  BDTR: u32,
+  DCR: u32,
+  DMAR: u32,
+}
+
+/// Auto-reload preload enable (ARR changes take effect at the next update)
+const TIM_CR1_ARPE: u32 = 1 << 7;
+/// One-pulse mode (the counter stops at the next update event)
+const TIM_CR1_OPM: u32 = 1 << 3;
+/// Only the counter overflows raise the update interrupt (not setting UG)
+const TIM_CR1_URS: u32 = 1 << 2;
+/// Counter enable
+const TIM_CR1_CEN: u32 = 1 << 0;
+
+/// Slave mode selection
+///  100: reset mode (the trigger input re-initializes the counter)
+const TIM_SMCR_SMS_RESET: u32 = 0b100 << 0;
+/// Trigger selection
+///  101: filtered timer input 1 (TI1FP1)
+///  110: filtered timer input 2 (TI2FP2)
+const TIM_SMCR_TS_SHIFT: u32 = 4;
+
+/// Capture/compare 2 interrupt enable
+const TIM_DIER_CC2IE: u32 = 1 << 2;
+/// Capture/compare 1 interrupt enable
+const TIM_DIER_CC1IE: u32 = 1 << 1;
+/// Update interrupt enable
+const TIM_DIER_UIE: u32 = 1 << 0;
+
+/// Capture/compare 2 interrupt flag (cleared by writing 0)
+const TIM_SR_CC2IF: u32 = 1 << 2;
+/// Capture/compare 1 interrupt flag (cleared by writing 0)
+const TIM_SR_CC1IF: u32 = 1 << 1;
+/// Update interrupt flag (cleared by writing 0)
+const TIM_SR_UIF: u32 = 1 << 0;
+
+/// Re-initialize the counter and load the prescaler
+const TIM_EGR_UG: u32 = 1 << 0;
+
+/// Offsets of CCMR1 and CCR1 in the register map (the other channels' ones
+/// follow them)
+const TIM_CCMR1_OFFSET: u32 = 0x18;
+const TIM_CCR1_OFFSET: u32 = 0x34;
+
+/// Output compare mode (of the channel's byte in CCMRx)
+///  110: PWM mode 1 (active while CNT < CCRx)
+const TIM_CCMR_OCM_PWM1: u32 = 0b110 << 4;
+const TIM_CCMR_OCM: u32 = 0b111 << 4;
+/// Output compare preload enable (CCRx changes take effect at the next update)
+const TIM_CCMR_OCPE: u32 = 1 << 3;
+/// Capture/compare selection
+///  00: output
+///  01: input, ICx mapped on its own TIx
+///  10: input, IC1 mapped on TI2, IC2 mapped on TI1
+const TIM_CCMR_CCS: u32 = 0b11 << 0;
+const TIM_CCMR_CCS_DIRECT: u32 = 0b01 << 0;
+const TIM_CCMR_CCS_INDIRECT: u32 = 0b10 << 0;
+
+/// Output enable (of the channel's nibble in CCER)
+const TIM_CCER_CCE: u32 = 1 << 0;
+/// Capture on the falling edge (as opposed to the rising one)
+const TIM_CCER_CCP: u32 = 1 << 1;
+/// Complementary output enable (TIM1 only)
+const TIM_CCER_CCNE: u32 = 1 << 2;
+
+/// Main output enable (TIM1 only, none of its outputs work without it)
+const TIM_BDTR_MOE: u32 = 1 << 15;
+/// Dead-time generator setup
+const TIM_BDTR_DTG: u32 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+  /// Fire once, after one period
+  OneShot,
+  /// Fire after every period, until stopped
+  Periodic,
+}
+
+/// Base address of the registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timer {
+  regmap: *mut TIM_register_map,
+}
+
+/// The advanced-control timer
+pub const TIM1: Timer = Timer { regmap: 0x4001_2c00 as *mut TIM_register_map };
+pub const TIM2: Timer = Timer { regmap: 0x4000_0000 as *mut TIM_register_map };
+pub const TIM3: Timer = Timer { regmap: 0x4000_0400 as *mut TIM_register_map };
+pub const TIM4: Timer = Timer { regmap: 0x4000_0800 as *mut TIM_register_map };
+
+/// The pins of the channels (without any remapping)
+const pins: [[(gpio::Gpio, u8); 4]; 4] = [
+  [(gpio::GPIOA, 8), (gpio::GPIOA, 9), (gpio::GPIOA, 10), (gpio::GPIOA, 11)],
+  [(gpio::GPIOA, 0), (gpio::GPIOA, 1), (gpio::GPIOA, 2), (gpio::GPIOA, 3)],
+  [(gpio::GPIOA, 6), (gpio::GPIOA, 7), (gpio::GPIOB, 0), (gpio::GPIOB, 1)],
+  [(gpio::GPIOB, 6), (gpio::GPIOB, 7), (gpio::GPIOB, 8), (gpio::GPIOB, 9)],
+];
+
+/// The complementary outputs of TIM1's channels 1-3
+const complementary_pins: [(gpio::Gpio, u8); 3] = [
+  (gpio::GPIOB, 13), (gpio::GPIOB, 14), (gpio::GPIOB, 15),
+];
+
+/// One period of the signal measured by the input capture, in the timer's
+/// ticks (see tick_frequency)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capture {
+  /// Between two rising edges
+  pub period: u32,
+  /// Between the rising edge and the falling one
+  pub high: u32,
+}
+
+/// The input capture in progress
+#[derive(Clone, Copy)]
+struct CaptureState {
+  /// The channel the signal comes in on (1 or 2)
+  channel: u8,
+  /// Counter overflows since the last rising edge
+  overflows: u32,
+  /// Rising edges seen so far (the first one only starts the measurement)
+  edges: u32,
+  last: Capture,
+}
+
+/// What gets called on the update events (one per timer)
+static mut callbacks: [Option<fn()>; 4] = [None; 4];
+static mut captures: [Option<CaptureState>; 4] = [None; 4];
+
+impl Timer {
+  /// 0 for TIM1, 1 for TIM2 and so on
+  fn index(&self) -> usize {
+    if *self == TIM1 {
+      0
+    } else {
+      (self.regmap as usize - TIM2.regmap as usize) / 0x400 + 1
+    }
+  }
+
+  fn irq(&self) -> nvic::Irq {
+    match self.index() {
+      0 => nvic::Irq::TIM1_UP,
+      1 => nvic::Irq::TIM2,
+      2 => nvic::Irq::TIM3,
+      _ => nvic::Irq::TIM4,
+    }
+  }
+
+  /// The frequency the prescaler gets
+  pub fn clock(&self) -> u32 {
+    if *self == TIM1 {
+      rcc::get_clock_speed(rcc::Clock::TIMCLK2)
+    } else {
+      rcc::get_clock_speed(rcc::Clock::TIMCLK1)
+    }
+  }
+
+  /// Enable the clock and set the prescaler and the auto-reload value so that
+  /// the counter overflows `frequency` times a second, returns the actual
+  /// frequency (the closest one that the 16-bit registers can give)
+  ///
+  /// The timer is left stopped, counting up.
+  pub fn initialize(self, frequency: u32) -> u32 {
+    match self.index() {
+      0 => rcc::enable(rcc::Periph::apb2_tim1),
+      1 => rcc::enable(rcc::Periph::apb1_tim2),
+      2 => rcc::enable(rcc::Periph::apb1_tim3),
+      _ => rcc::enable(rcc::Periph::apb1_tim4),
+    }
+
+    unsafe {
+      (*self.regmap).CR1 = TIM_CR1_URS | TIM_CR1_ARPE;
+      (*self.regmap).DIER = 0;
+      (*self.regmap).SR = 0;
+    }
+
+    self.set_frequency(frequency)
+  }
+
+  /// Change the period (while running too), returns the actual frequency
+  pub fn set_frequency(&self, frequency: u32) -> u32 {
+    let clock = self.clock();
+    let (prescaler, reload) = divisors(clock, frequency);
+
+    unsafe {
+      (*self.regmap).PSC = prescaler - 1;
+      (*self.regmap).ARR = reload - 1;
+
+      // The prescaler only gets loaded on an update event
+      if (*self.regmap).CR1 & TIM_CR1_CEN == 0 {
+        (*self.regmap).EGR = TIM_EGR_UG;
+      }
+    }
+
+    let actual = clock / (prescaler * reload);
+
+    debug!("Timer at {:p} configured, PSC = {}, ARR = {}, f = {} Hz", self.regmap, prescaler - 1, reload - 1, actual);
+
+    actual
+  }
+
+  /// Start counting, `callback` gets called from the interrupt handler on
+  /// every overflow (or just the first one in the one-shot mode)
+  pub fn start(&self, mode: Mode, callback: Option<fn()>) {
+    unsafe {
+      callbacks[self.index()] = callback;
+
+      (*self.regmap).SR = !TIM_SR_UIF;
+
+      match mode {
+        Mode::OneShot => (*self.regmap).CR1 |= TIM_CR1_OPM,
+        Mode::Periodic => (*self.regmap).CR1 &= !TIM_CR1_OPM,
+      }
+
+      match callback {
+        Some(_) => {
+          (*self.regmap).DIER |= TIM_DIER_UIE;
+          nvic::enable_irq(self.irq());
+        },
+        None => (*self.regmap).DIER &= !TIM_DIER_UIE,
+      }
+
+      // Start from 0 with the ARR and PSC that set_frequency left in the
+      // preload registers, or a one-shot fires early (URS keeps the UG from
+      // raising the interrupt)
+      (*self.regmap).EGR = TIM_EGR_UG;
+      (*self.regmap).CR1 |= TIM_CR1_CEN;
+    }
+  }
+
+  /// Stop counting (the counter keeps its value)
+  pub fn stop(&self) {
+    unsafe {
+      (*self.regmap).CR1 &= !TIM_CR1_CEN;
+      (*self.regmap).DIER &= !TIM_DIER_UIE;
+      (*self.regmap).SR = !TIM_SR_UIF;
+
+      callbacks[self.index()] = None;
+    }
+  }
+
+  /// Whether it's counting (a one-shot timer stops by itself)
+  pub fn is_running(&self) -> bool {
+    unsafe { (*self.regmap).CR1 & TIM_CR1_CEN != 0 }
+  }
+
+  pub fn counter(&self) -> u16 {
+    unsafe { (*self.regmap).CNT as u16 }
+  }
+
+  pub fn set_counter(&self, value: u16) {
+    unsafe {
+      (*self.regmap).CNT = value as u32;
+    }
+  }
+
+  /// The counter goes from 0 up to this value
+  pub fn auto_reload(&self) -> u16 {
+    unsafe { (*self.regmap).ARR as u16 }
+  }
+
+  /// How many times a second the counter ticks
+  pub fn tick_frequency(&self) -> u32 {
+    self.clock() / unsafe { (*self.regmap).PSC + 1 }
+  }
+
+  /// The pin of the channel (1-4)
+  pub fn channel_pin(&self, channel: u8) -> Option<(gpio::Gpio, u8)> {
+    match channel {
+      1...4 => Some(pins[self.index()][channel as usize - 1]),
+      _ => None,
+    }
+  }
+
+  /// The pin of the channel's complementary output (TIM1 channels 1-3 only)
+  pub fn complementary_pin(&self, channel: u8) -> Option<(gpio::Gpio, u8)> {
+    match channel {
+      1...3 if *self == TIM1 => Some(complementary_pins[channel as usize - 1]),
+      _ => None,
+    }
+  }
+
+  /// CCMRx and the shift of the channel's byte in it
+  fn ccmr(&self, channel: u8) -> (*mut u32, u32) {
+    let offset = TIM_CCMR1_OFFSET + 4 * ((channel as u32 - 1) / 2);
+
+    ((self.regmap as u32 + offset) as *mut u32, 8 * ((channel as u32 - 1) % 2))
+  }
+
+  fn ccr(&self, channel: u8) -> *mut u32 {
+    (self.regmap as u32 + TIM_CCR1_OFFSET + 4 * (channel as u32 - 1)) as *mut u32
+  }
+
+  /// Output PWM on the channel (and its complementary output when asked to,
+  /// TIM1 only), with the frequency of the timer and the duty cycle set by
+  /// set_duty (0 until then), returns false if there's no such output
+  ///
+  /// The pins get switched to the alternate function. All the channels of
+  /// the timer share the frequency.
+  pub fn start_pwm(&self, channel: u8, complementary: bool) -> bool {
+    let (port, pin) = match self.channel_pin(channel) {
+      Some(output) => output,
+      None => return false,
+    };
+
+    let negated = if complementary {
+      match self.complementary_pin(channel) {
+        Some(output) => Some(output),
+        None => return false,
+      }
+    } else {
+      None
+    };
+
+    port.set_pin_mode(pin, gpio::PinMode::OutAltPP);
+    port.set_pin_speed(pin, gpio::PinSpeed::Max50MHz);
+
+    if let Some((port, pin)) = negated {
+      port.set_pin_mode(pin, gpio::PinMode::OutAltPP);
+      port.set_pin_speed(pin, gpio::PinSpeed::Max50MHz);
+    }
+
+    let (ccmr, shift) = self.ccmr(channel);
+    let ccer_shift = 4 * (channel as u32 - 1);
+
+    unsafe {
+      *self.ccr(channel) = 0;
+
+      *ccmr &= !((TIM_CCMR_OCM | TIM_CCMR_OCPE | TIM_CCMR_CCS) << shift);
+      *ccmr |= (TIM_CCMR_OCM_PWM1 | TIM_CCMR_OCPE) << shift;
+
+      (*self.regmap).CCER &= !((TIM_CCER_CCE | TIM_CCER_CCNE) << ccer_shift);
+      (*self.regmap).CCER |= TIM_CCER_CCE << ccer_shift;
+
+      if negated.is_some() {
+        (*self.regmap).CCER |= TIM_CCER_CCNE << ccer_shift;
+      }
+
+      if *self == TIM1 {
+        (*self.regmap).BDTR |= TIM_BDTR_MOE;
+      }
+
+      (*self.regmap).CR1 |= TIM_CR1_ARPE | TIM_CR1_CEN;
+    }
+
+    true
+  }
+
+  /// Turn the channel's outputs off (the timer stops with the last one)
+  pub fn stop_pwm(&self, channel: u8) {
+    if !self.outputs_pwm(channel) {
+      return;
+    }
+
+    unsafe {
+      (*self.regmap).CCER &= !((TIM_CCER_CCE | TIM_CCER_CCNE) << (4 * (channel as u32 - 1)));
+    }
+
+    if !self.is_pwm_running() {
+      unsafe {
+        (*self.regmap).CR1 &= !TIM_CR1_CEN;
+      }
+    }
+  }
+
+  /// Whether the channel (1-4) was set up by start_pwm and still outputs
+  fn outputs_pwm(&self, channel: u8) -> bool {
+    if self.channel_pin(channel).is_none() {
+      return false;
+    }
+
+    let (ccmr, shift) = self.ccmr(channel);
+    let mode = unsafe { *ccmr >> shift } & (TIM_CCMR_OCM | TIM_CCMR_CCS);
+    let ccer = unsafe { (*self.regmap).CCER >> (4 * (channel as u32 - 1)) };
+
+    mode == TIM_CCMR_OCM_PWM1 && ccer & TIM_CCER_CCE != 0
+  }
+
+  /// Whether any of the channels outputs PWM (so the timer counts for them)
+  pub fn is_pwm_running(&self) -> bool {
+    (1..5).any(|channel| self.outputs_pwm(channel))
+  }
+
+  /// Set the duty cycle (in percent) of the channel
+  pub fn set_duty(&self, channel: u8, percent: u8) {
+    let percent = if percent > 100 { 100 } else { percent as u32 };
+
+    // The output's active while CNT < CCRx, so ARR + 1 keeps it on all the
+    // time
+    let period = unsafe { (*self.regmap).ARR } + 1;
+
+    self.set_compare(channel, (period * percent / 100) as u16);
+  }
+
+  /// Set the raw compare value of the channel (1-4)
+  pub fn set_compare(&self, channel: u8, value: u16) {
+    if self.channel_pin(channel).is_none() {
+      return;
+    }
+
+    unsafe {
+      *self.ccr(channel) = value as u32;
+    }
+  }
+
+  /// Set the dead time inserted between the channel and its complementary
+  /// output switching (TIM1 only), returns the actual one in ns
+  pub fn set_dead_time(&self, ns: u32) -> u32 {
+    if *self != TIM1 {
+      return 0;
+    }
+
+    let ns = if ns > 1_000_000 { 1_000_000 } else { ns };
+    let mhz = self.clock() / 1_000_000;
+    // In the timer's clock periods (CKD is left at 1)
+    let (dtg, actual) = dead_time(ns * mhz / 1000);
+
+    unsafe {
+      (*self.regmap).BDTR = ((*self.regmap).BDTR & !TIM_BDTR_DTG) | dtg;
+    }
+
+    actual * 1000 / mhz
+  }
+
+  /// Measure the signal on the pin of channel 1 or 2 (in the PWM input mode:
+  /// the rising edges reset the counter, one channel captures the period and
+  /// the other one the high time), with the counter ticking as close to
+  /// `tick_frequency` times a second as the prescaler lets it
+  ///
+  /// The counter overflows are counted too, so slow signals can be measured
+  /// with a fine resolution (up to 2^32 ticks per period). Returns false if
+  /// there's no such channel.
+  ///
+  /// The pin is switched to a floating input and stays one after
+  /// stop_capture, so it mustn't be one that a peripheral drives.
+  pub fn start_capture(self, channel: u8, tick_frequency: u32) -> bool {
+    if channel != 1 && channel != 2 {
+      return false;
+    }
+
+    // Set up the clock and the rest, the frequency is overwritten below
+    self.initialize(1);
+
+    let (port, pin) = pins[self.index()][channel as usize - 1];
+    port.set_pin_mode(pin, gpio::PinMode::InFloat);
+
+    let mut prescaler = if tick_frequency == 0 { 1 } else { self.clock() / tick_frequency };
+    if prescaler < 1 {
+      prescaler = 1;
+    } else if prescaler > 0x1_0000 {
+      prescaler = 0x1_0000;
+    }
+
+    // The input channel captures the rising edges (the period), the other one
+    // gets the same input and captures the falling edges (the high time)
+    let (ccmr, trigger) = if channel == 1 {
+      (TIM_CCMR_CCS_DIRECT | TIM_CCMR_CCS_INDIRECT << 8, 0b101)
+    } else {
+      (TIM_CCMR_CCS_INDIRECT | TIM_CCMR_CCS_DIRECT << 8, 0b110)
+    };
+
+    let falling_shift = if channel == 1 { 4 } else { 0 };
+
+    unsafe {
+      captures[self.index()] = Some(CaptureState {
+        channel: channel,
+        overflows: 0,
+        edges: 0,
+        last: Capture { period: 0, high: 0 },
+      });
+
+      (*self.regmap).PSC = prescaler - 1;
+      (*self.regmap).ARR = 0xffff;
+      (*self.regmap).EGR = TIM_EGR_UG;
+
+      (*self.regmap).CCER = 0;
+      (*self.regmap).CCMR1 = ccmr;
+      (*self.regmap).CCER = (TIM_CCER_CCE | TIM_CCER_CCE << 4) | TIM_CCER_CCP << falling_shift;
+
+      (*self.regmap).SMCR = trigger << TIM_SMCR_TS_SHIFT | TIM_SMCR_SMS_RESET;
+
+      (*self.regmap).SR = 0;
+      (*self.regmap).DIER = TIM_DIER_UIE | TIM_DIER_CC1IE | TIM_DIER_CC2IE;
+    }
+
+    nvic::enable_irq(self.irq());
+    if self == TIM1 {
+      nvic::enable_irq(nvic::Irq::TIM1_CC);
+    }
+
+    unsafe {
+      (*self.regmap).CR1 |= TIM_CR1_CEN;
+    }
+
+    true
+  }
+
+  pub fn stop_capture(&self) {
+    unsafe {
+      if captures[self.index()].is_none() {
+        return;
+      }
+
+      (*self.regmap).CR1 &= !TIM_CR1_CEN;
+      (*self.regmap).DIER = 0;
+      (*self.regmap).SMCR = 0;
+      (*self.regmap).CCER = 0;
+      (*self.regmap).CCMR1 = 0;
+      (*self.regmap).SR = 0;
+
+      captures[self.index()] = None;
+    }
+
+    if *self == TIM1 {
+      nvic::disable_irq(nvic::Irq::TIM1_CC);
+    }
+  }
+
+  /// The last full period of the signal, None until one is measured or when
+  /// there haven't been any rising edges for over two periods (the signal's
+  /// gone)
+  pub fn read_capture(&self) -> Option<Capture> {
+    let state = nvic::without_interrupts(|| unsafe { captures[self.index()] });
+
+    match state {
+      Some(state) if state.edges >= 2 => {
+        let since_edge = state.overflows.saturating_mul(0x1_0000);
+
+        if since_edge / 2 > state.last.period {
+          None
+        } else {
+          Some(state.last)
+        }
+      },
+      _ => None,
+    }
+  }
+
+  fn handle_capture_irq(&self, state: &mut CaptureState) {
+    let sr = unsafe { (*self.regmap).SR };
+    let handled = sr & (TIM_SR_UIF | TIM_SR_CC1IF | TIM_SR_CC2IF);
+
+    unsafe {
+      (*self.regmap).SR = !handled;
+    }
+
+    let (rising, falling) = if state.channel == 1 {
+      (TIM_SR_CC1IF, TIM_SR_CC2IF)
+    } else {
+      (TIM_SR_CC2IF, TIM_SR_CC1IF)
+    };
+
+    let overflow = sr & TIM_SR_UIF != 0;
+
+    if sr & falling != 0 {
+      let value = unsafe { *self.ccr(if state.channel == 1 { 2 } else { 1 }) };
+
+      // An overflow that came along is only before the edge if the counter
+      // didn't get far after it
+      let overflows = if overflow && value < 0x8000 {
+        state.overflows.saturating_add(1)
+      } else {
+        state.overflows
+      };
+
+      state.last.high = overflows.saturating_mul(0x1_0000).saturating_add(value);
+    }
+
+    if sr & rising != 0 {
+      let value = unsafe { *self.ccr(state.channel) };
+
+      // The counter got reset by the edge, so any overflow came before it
+      let overflows = if overflow { state.overflows.saturating_add(1) } else { state.overflows };
+
+      state.last.period = overflows.saturating_mul(0x1_0000).saturating_add(value);
+      state.overflows = 0;
+      state.edges = state.edges.saturating_add(1);
+    } else if overflow {
+      state.overflows = state.overflows.saturating_add(1);
+    }
+  }
+
+  fn handle_irq(self) {
+    unsafe {
+      if let Some(ref mut state) = captures[self.index()] {
+        self.handle_capture_irq(state);
+        return;
+      }
+
+      if (*self.regmap).SR & TIM_SR_UIF == 0 {
+        return;
+      }
+
+      (*self.regmap).SR = !TIM_SR_UIF;
+
+      match callbacks[self.index()] {
+        Some(callback) => callback(),
+        None => (),
+      }
+    }
+  }
+}
+
+/// The prescaler and the auto-reload value (both off by one from what goes
+/// into PSC and ARR) that make a `clock` Hz counter overflow closest to
+/// `frequency` times a second
+fn divisors(clock: u32, frequency: u32) -> (u32, u32) {
+  let mut ticks = if frequency == 0 { clock } else { clock / frequency };
+  if ticks < 2 {
+    ticks = 2;
+  }
+
+  // Keep the prescaler as small as possible, for the best resolution
+  let mut prescaler = (ticks + 0xffff) / 0x1_0000;
+  if prescaler > 0x1_0000 {
+    prescaler = 0x1_0000;
+  }
+
+  let mut reload = (ticks + prescaler / 2) / prescaler;
+  if reload > 0x1_0000 {
+    reload = 0x1_0000;
+  }
+
+  (prescaler, reload)
+}
+
+/// The DTG value for a dead time of `ticks` clock periods, and the dead time
+/// it actually gives (in clock periods)
+fn dead_time(ticks: u32) -> (u32, u32) {
+  // DTG[7:5] picks the step: 1, 2, 8 or 16 periods
+  if ticks < 128 {
+    (ticks, ticks)
+  } else if ticks < 256 {
+    let steps = ticks / 2 - 64;
+    (0b1000_0000 | steps, (64 + steps) * 2)
+  } else if ticks < 512 {
+    let steps = ticks / 8 - 32;
+    (0b1100_0000 | steps, (32 + steps) * 8)
+  } else {
+    let steps = if ticks / 16 - 32 > 31 { 31 } else { ticks / 16 - 32 };
+    (0b1110_0000 | steps, (32 + steps) * 16)
+  }
+}
+
+/// The timer and the channel (1 or 2) that can measure the signal on the pin
+pub fn capture_input(port: gpio::Gpio, pin: u8) -> Option<(Timer, u8)> {
+  let timers = [TIM1, TIM2, TIM3, TIM4];
+
+  for (i, channels) in pins.iter().enumerate() {
+    for channel in 0..2 {
+      if channels[channel] == (port, pin) {
+        return Some((timers[i], channel as u8 + 1));
+      }
+    }
+  }
+
+  None
+}
+
+pub extern "C" fn tim1_up_irq_handler() {
+  TIM1.handle_irq();
+}
+
+pub extern "C" fn tim1_cc_irq_handler() {
+  TIM1.handle_irq();
+}
+
+pub extern "C" fn tim2_irq_handler() {
+  TIM2.handle_irq();
+}
+
+pub extern "C" fn tim3_irq_handler() {
+  TIM3.handle_irq();
+}
+
+pub extern "C" fn tim4_irq_handler() {
+  TIM4.handle_irq();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn divisors_keep_the_prescaler_small() {
+    // 1 kHz off 72 MHz needs a prescaler of 2, 10 kHz fits in ARR alone
+    assert_eq!(divisors(72_000_000, 1_000), (2, 36_000));
+    assert_eq!(divisors(72_000_000, 10_000), (1, 7_200));
+  }
+
+  #[test]
+  fn divisors_clamp() {
+    // Slower than the 16-bit registers go
+    assert_eq!(divisors(72_000_000, 0), (1_099, 65_514));
+    // Faster than the counter can overflow
+    assert_eq!(divisors(72_000_000, 72_000_000), (1, 2));
+  }
+
+  #[test]
+  fn dead_time_steps() {
+    assert_eq!(dead_time(100), (100, 100));
+    assert_eq!(dead_time(200), (0b1000_0000 | 36, 200));
+    assert_eq!(dead_time(300), (0b1100_0000 | 5, 296));
+    assert_eq!(dead_time(1000), (0b1110_0000 | 30, 992));
+    // Past the longest one (1008 periods)
+    assert_eq!(dead_time(5000), (0b1110_0000 | 31, 1008));
+  }
+}
+
+/*
+ * vi: ts=2 sw=2 expandtab
+ */
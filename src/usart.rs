@@ -10,23 +10,68 @@ use core::fmt;
 
 use rcc;
 use gpio;
-
+use afio;
+use nvic;
+use ringbuf::RingBuf;
+
+/// Parity error
+const USART_SR_PE: u32 = 1 << 0;
+/// Framing error (de-synchronization, excessive noise or a break character)
+const USART_SR_FE: u32 = 1 << 1;
+/// Noise detected on the received frame
+const USART_SR_NE: u32 = 1 << 2;
+/// Overrun error (a byte arrived while the previous one was still in DR)
+const USART_SR_ORE: u32 = 1 << 3;
 /// Read data register not empty (data ready to be read)
 const USART_SR_RXNE: u32 = 1 << 5;
 /// Transmission complete
 const USART_SR_TC: u32 = 1 << 6;
 /// Transmitter data register empty (ie. can send bytes?)
 const USART_SR_TXE: u32 = 1 << 7;
+/// LIN break detected
+const USART_SR_LBD: u32 = 1 << 8;
 /// UART enable bit
 const USART_CR1_UE: u32 = 1 << 13;
+/// Transmitter data register empty interrupt enable
+const USART_CR1_TXEIE: u32 = 1 << 7;
 /// Selects the word length:
 ///   0 - 1 start bit, 8 data bits, n stop bit
 ///   1 - 1 start bit, 9 data bits, n stop bit
-const USART_CR1_M: u32 = 1 << 1;
+const USART_CR1_M: u32 = 1 << 12;
+/// Parity control enable
+const USART_CR1_PCE: u32 = 1 << 10;
+/// Parity selection (odd when set)
+const USART_CR1_PS: u32 = 1 << 9;
+/// Send break
+const USART_CR1_SBK: u32 = 1 << 0;
 /// Receiver enable
 const USART_CR1_RE: u32 = 1 << 2;
 /// Transmitter enable
 const USART_CR1_TE: u32 = 1 << 3;
+/// LIN break detection length
+///   0 - 10 bits
+///   1 - 11 bits
+const USART_CR2_LBDL: u32 = 1 << 5;
+/// Clock (CK pin) enable
+const USART_CR2_CLKEN: u32 = 1 << 11;
+/// Number of stop bits
+///   00 - 1 stop bit
+///   01 - 0.5 stop bit
+///   10 - 2 stop bits
+///   11 - 1.5 stop bit
+const USART_CR2_STOP: u32 = 0b11 << 12;
+/// LIN mode enable
+const USART_CR2_LINEN: u32 = 1 << 14;
+/// IrDA mode enable
+const USART_CR3_IREN: u32 = 1 << 1;
+/// IrDA low-power mode
+const USART_CR3_IRLP: u32 = 1 << 2;
+/// Half-duplex (single-wire) mode selection
+const USART_CR3_HDSEL: u32 = 1 << 3;
+/// Smartcard NACK enable (on parity errors)
+const USART_CR3_NACK: u32 = 1 << 4;
+/// Smartcard mode enable
+const USART_CR3_SCEN: u32 = 1 << 5;
 
 #[repr(packed)]
 struct Usart_register_map {
@@ -50,16 +95,86 @@ pub const USART3: Usart = Usart(0x4000_4800, rcc::Clock::PCLK1);
 
 pub static mut current: Option<Usart> = Some(USART2);
 
+/// Bytes waiting to be moved to DR by the TXE interrupt (one per port)
+static mut tx_buffers: [RingBuf; 3] = [RingBuf::new(), RingBuf::new(), RingBuf::new()];
+/// Whether the port's transmissions go through `tx_buffers`
+static mut tx_buffered: [bool; 3] = [false; 3];
+
+/// Reception errors, as reported by the status register
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+  /// At least one byte was lost because DR wasn't read in time
+  Overrun,
+  Framing,
+  Noise,
+  Parity,
+}
+
+/// How many bytes went through the port and how many reception errors were
+/// spotted along the way
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+  pub received: u32,
+  pub sent: u32,
+  pub overrun: u32,
+  pub framing: u32,
+  pub noise: u32,
+  pub parity: u32,
+}
+
+const EMPTY_STATS: Stats = Stats { received: 0, sent: 0, overrun: 0, framing: 0, noise: 0, parity: 0 };
+
+static mut port_stats: [Stats; 3] = [EMPTY_STATS; 3];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinBreakLength {
+  Bits10,
+  Bits11,
+}
+
+/// The special modes the USART can work in besides the regular full-duplex
+/// one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+  Normal,
+  /// TX and RX share the TX pin, which is switched to open-drain and has to
+  /// be pulled up externally. Mind that everything sent is also received.
+  HalfDuplex,
+  /// Break characters can be sent with send_break and detected with
+  /// break_detected
+  Lin(LinBreakLength),
+  /// SIR encoding, the prescaler has to be 1 unless in low-power mode (where
+  /// it divides PCLK to give the low-power frequency)
+  IrDA { low_power: bool, prescaler: u8 },
+  /// 9 bits with even parity and 1.5 stop bits, TX is switched to open-drain
+  /// and the card's clock goes out through CK at PCLK / (2 * prescaler). The
+  /// guard time is in bit periods.
+  Smartcard { prescaler: u8, guard_time: u8, nack: bool },
+}
+
 #[derive(Copy,Clone)]
 pub enum Baudrate {
   _9600   = 9600,
   _115200 = 115200,
 }
 
+/// Whether nothing can drain the transmit buffers right now: the TXE
+/// interrupt doesn't get to run while the interrupts are masked or while
+/// another handler runs (they all share one priority)
+fn tx_irq_blocked() -> bool {
+  nvic::interrupts_disabled() || nvic::in_handler()
+}
+
 impl Usart {
+  /// Enables the port's clock, sets up its pins and the baud rate
+  ///
+  /// USART1 uses PB6/PB7 instead of PA9/PA10 if it was remapped (see
+  /// afio::remap) before calling this
   pub fn initialize(self, baudrate: Baudrate) {
     let regmap = self.0 as *mut Usart_register_map;
 
+    rcc::enable(self.periph());
+
     let mut usartdiv = 0;
     let mut clock_speed = rcc::get_clock_speed(self.1);
 
@@ -85,16 +200,212 @@ impl Usart {
       (*regmap).CR1 |= USART_CR1_UE;
     }
 
-    if self == USART2 {
-      // Set the USART pins
-      gpio::GPIOA.set_pin_mode(2, gpio::PinMode::OutAltPP);
-      gpio::GPIOA.set_pin_mode(3, gpio::PinMode::InFloat);
+    // Set the USART pins
+    let ((tx_port, tx_pin), (rx_port, rx_pin)) = self.pins();
+
+    tx_port.set_pin_mode(tx_pin, gpio::PinMode::OutAltPP);
+    rx_port.set_pin_mode(rx_pin, gpio::PinMode::InFloat);
+  }
+
+  /// Switch the port into one of the special modes (or back to the normal
+  /// one)
+  ///
+  /// The port is disabled for the time of reconfiguration.
+  pub fn set_mode(self, mode: Mode) {
+    let regmap = self.0 as *mut Usart_register_map;
+    let ((tx_port, tx_pin), _) = self.pins();
+    let (ck_port, ck_pin) = self.ck_pin();
+
+    unsafe {
+      (*regmap).CR1 &= !USART_CR1_UE;
+
+      // Start from scratch, as most of the modes are mutually exclusive
+      (*regmap).CR1 &= !(USART_CR1_M | USART_CR1_PCE | USART_CR1_PS);
+      (*regmap).CR2 &= !(USART_CR2_LINEN | USART_CR2_LBDL | USART_CR2_CLKEN | USART_CR2_STOP);
+      (*regmap).CR3 &= !(USART_CR3_IREN | USART_CR3_IRLP | USART_CR3_HDSEL | USART_CR3_NACK | USART_CR3_SCEN);
+      (*regmap).GTPR = 0;
+
+      match mode {
+        Mode::Normal => {
+          tx_port.set_pin_mode(tx_pin, gpio::PinMode::OutAltPP);
+        },
+        Mode::HalfDuplex => {
+          tx_port.set_pin_mode(tx_pin, gpio::PinMode::OutAltDrain);
+          (*regmap).CR3 |= USART_CR3_HDSEL;
+        },
+        Mode::Lin(length) => {
+          tx_port.set_pin_mode(tx_pin, gpio::PinMode::OutAltPP);
+
+          if length == LinBreakLength::Bits11 {
+            (*regmap).CR2 |= USART_CR2_LBDL;
+          }
+
+          (*regmap).CR2 |= USART_CR2_LINEN;
+        },
+        Mode::IrDA { low_power, prescaler } => {
+          tx_port.set_pin_mode(tx_pin, gpio::PinMode::OutAltPP);
+
+          (*regmap).GTPR = prescaler as u32;
+
+          if low_power {
+            (*regmap).CR3 |= USART_CR3_IRLP;
+          }
+
+          (*regmap).CR3 |= USART_CR3_IREN;
+        },
+        Mode::Smartcard { prescaler, guard_time, nack } => {
+          tx_port.set_pin_mode(tx_pin, gpio::PinMode::OutAltDrain);
+          ck_port.set_pin_mode(ck_pin, gpio::PinMode::OutAltPP);
+
+          (*regmap).GTPR = (guard_time as u32) << 8 | prescaler as u32;
+          // 9 bits with even parity (PS was cleared above), as ISO 7816 wants
+          (*regmap).CR1 |= USART_CR1_M | USART_CR1_PCE;
+          (*regmap).CR2 |= USART_CR2_STOP | USART_CR2_CLKEN;
+
+          if nack {
+            (*regmap).CR3 |= USART_CR3_NACK;
+          }
+
+          (*regmap).CR3 |= USART_CR3_SCEN;
+        },
+      }
+
+      (*regmap).CR1 |= USART_CR1_UE;
+    }
+  }
+
+  /// Transmit a break character (the hardware sends it out once the current
+  /// byte is done)
+  pub fn send_break(&self) {
+    let regmap = self.0 as *mut Usart_register_map;
+
+    unsafe {
+      (*regmap).CR1 |= USART_CR1_SBK;
+
+      // SBK gets cleared by the hardware during the stop bit of the break
+      while (*regmap).CR1 & USART_CR1_SBK != 0 {}
+    }
+  }
+
+  /// Whether a LIN break was detected since the last call (LIN mode only)
+  ///
+  /// A break also shows up as a framing error with a zero byte on reception.
+  pub fn break_detected(&self) -> bool {
+    let regmap = self.0 as *mut Usart_register_map;
+
+    unsafe {
+      if (*regmap).SR & USART_SR_LBD == 0 {
+        return false;
+      }
+
+      // LBD is cleared by writing a zero to it
+      (*regmap).SR = !USART_SR_LBD;
+    }
+
+    true
+  }
+
+  fn periph(&self) -> rcc::Periph {
+    if *self == USART1 {
+      rcc::Periph::apb2_usart1
+    } else if *self == USART2 {
+      rcc::Periph::apb1_usart2
+    } else {
+      rcc::Periph::apb1_usart3
+    }
+  }
+
+  /// The (port, pin) pairs of TX and RX
+  fn pins(&self) -> ((gpio::Gpio, u8), (gpio::Gpio, u8)) {
+    if *self == USART1 {
+      if afio::is_remapped(afio::Remap::USART1) {
+        ((gpio::GPIOB, 6), (gpio::GPIOB, 7))
+      } else {
+        ((gpio::GPIOA, 9), (gpio::GPIOA, 10))
+      }
+    } else if *self == USART2 {
+      ((gpio::GPIOA, 2), (gpio::GPIOA, 3))
+    } else {
+      ((gpio::GPIOB, 10), (gpio::GPIOB, 11))
+    }
+  }
+
+  /// The clock output pin (used in the smartcard mode)
+  fn ck_pin(&self) -> (gpio::Gpio, u8) {
+    if *self == USART1 {
+      (gpio::GPIOA, 8)
+    } else if *self == USART2 {
+      (gpio::GPIOA, 4)
+    } else {
+      (gpio::GPIOB, 12)
+    }
+  }
+
+  fn index(&self) -> usize {
+    if *self == USART1 {
+      0
+    } else if *self == USART2 {
+      1
+    } else {
+      2
+    }
+  }
+
+  fn irq(&self) -> nvic::Irq {
+    if *self == USART1 {
+      nvic::Irq::USART1
+    } else if *self == USART2 {
+      nvic::Irq::USART2
+    } else {
+      nvic::Irq::USART3
+    }
+  }
+
+  /// Make the transmissions go through a ring buffer which gets drained by
+  /// the TXE interrupt, so that sending doesn't have to wait for the wire
+  /// (unless the buffer fills up)
+  pub fn enable_tx_buffering(self) {
+    unsafe {
+      tx_buffered[self.index()] = true;
     }
+
+    nvic::enable_irq(self.irq());
+  }
+
+  /// Go back to polled transmission (whatever is still buffered is sent out
+  /// first)
+  pub fn disable_tx_buffering(self) {
+    self.flush();
+
+    nvic::disable_irq(self.irq());
+
+    unsafe {
+      tx_buffered[self.index()] = false;
+    }
+  }
+
+  fn is_tx_buffered(&self) -> bool {
+    unsafe { tx_buffered[self.index()] }
   }
 
   pub fn send_byte(&self, byte: u8) {
     let regmap = self.0 as *mut Usart_register_map;
 
+    if self.is_tx_buffered() {
+      if !tx_irq_blocked() {
+        // Wait until there's space in the buffer
+        while !self.try_send_byte(byte) {}
+        return;
+      }
+
+      // Make space by sending the oldest bytes by hand (that keeps them in
+      // order, and this can't race the interrupt handler now)
+      while !self.try_send_byte(byte) {
+        self.send_buffered_byte();
+      }
+      return;
+    }
+
     // Wait until there's space for transmission
     unsafe {
       while (*regmap).SR & USART_SR_TXE == 0 {}
@@ -102,18 +413,170 @@ impl Usart {
       // Actually transmit the data
       (*regmap).DR = byte as u32;
 
-      // Wait until the transmission is complete
-      while (*regmap).SR & USART_SR_TC != 0 {}
+      port_stats[self.index()].sent += 1;
     }
   }
 
-  pub fn get_byte(&self) -> u8 {
+  /// Send the byte (or queue it, if the port is buffered) only if that can be
+  /// done without waiting. Returns false if the byte was dropped.
+  ///
+  /// It's fine to call it from the interrupt handlers, the buffer is only
+  /// ever filled with the interrupts disabled.
+  pub fn try_send_byte(&self, byte: u8) -> bool {
+    let regmap = self.0 as *mut Usart_register_map;
+
+    if !self.is_tx_buffered() {
+      unsafe {
+        if (*regmap).SR & USART_SR_TXE == 0 {
+          return false;
+        }
+
+        (*regmap).DR = byte as u32;
+
+        port_stats[self.index()].sent += 1;
+      }
+
+      return true;
+    }
+
+    // The ring has a single producer, this makes everybody who prints one
+    nvic::without_interrupts(|| unsafe {
+      if !tx_buffers[self.index()].push(byte) {
+        return false;
+      }
+
+      // (Re)start draining the buffer, the interrupt handler turns this off
+      // once it runs out of bytes
+      (*regmap).CR1 |= USART_CR1_TXEIE;
+
+      true
+    })
+  }
+
+  /// Wait for the wire and send the oldest buffered byte, in place of the
+  /// interrupt handler (only when that can't run, see tx_irq_blocked)
+  fn send_buffered_byte(&self) {
+    let regmap = self.0 as *mut Usart_register_map;
+
+    unsafe {
+      while (*regmap).SR & USART_SR_TXE == 0 {}
+
+      if let Some(byte) = tx_buffers[self.index()].pop() {
+        (*regmap).DR = byte as u32;
+        port_stats[self.index()].sent += 1;
+      }
+    }
+  }
+
+  /// Wait until everything that was queued went out through the wire
+  pub fn flush(&self) {
     let regmap = self.0 as *mut Usart_register_map;
 
+    while unsafe { !tx_buffers[self.index()].is_empty() } {
+      if tx_irq_blocked() {
+        self.send_buffered_byte();
+      }
+    }
+
     unsafe {
-      while (*regmap).SR & USART_SR_RXNE == 0 {}
+      while (*regmap).SR & USART_SR_TC == 0 {}
+    }
+  }
+
+  fn handle_irq(self) {
+    let regmap = self.0 as *mut Usart_register_map;
 
-      (*regmap).DR as u8
+    unsafe {
+      if (*regmap).CR1 & USART_CR1_TXEIE != 0 && (*regmap).SR & USART_SR_TXE != 0 {
+        match tx_buffers[self.index()].pop() {
+          Some(byte) => {
+            (*regmap).DR = byte as u32;
+            port_stats[self.index()].sent += 1;
+          },
+          // Nothing more to send, stop the interrupts (they would fire
+          // continuously otherwise since DR stays empty)
+          None => (*regmap).CR1 &= !USART_CR1_TXEIE,
+        }
+      }
+    }
+  }
+
+  /// Wait for a byte and return it along with the error flags that came with
+  /// it
+  ///
+  /// Reading SR and then DR (in that order) is what clears the error flags.
+  fn receive(&self) -> (u32, u8) {
+    let regmap = self.0 as *mut Usart_register_map;
+
+    unsafe {
+      while (*regmap).SR & (USART_SR_RXNE | USART_SR_ORE) == 0 {}
+
+      let sr = (*regmap).SR;
+      let byte = (*regmap).DR as u8;
+
+      let stats = &mut port_stats[self.index()];
+
+      stats.received += 1;
+
+      if sr & USART_SR_ORE != 0 { stats.overrun += 1; }
+      if sr & USART_SR_FE != 0 { stats.framing += 1; }
+      if sr & USART_SR_NE != 0 { stats.noise += 1; }
+      if sr & USART_SR_PE != 0 { stats.parity += 1; }
+
+      (sr, byte)
+    }
+  }
+
+  /// Whether the port is enabled and the pin is its TX or RX
+  pub fn owns_pin(&self, port: gpio::Gpio, pin: u8) -> bool {
+    let regmap = self.0 as *mut Usart_register_map;
+    let (tx, rx) = self.pins();
+    let enabled = unsafe { (*regmap).CR1 & USART_CR1_UE != 0 };
+
+    enabled && (tx == (port, pin) || rx == (port, pin))
+  }
+
+  /// Whether get_byte (or read_byte) would return right away
+  pub fn has_byte(&self) -> bool {
+    let regmap = self.0 as *mut Usart_register_map;
+
+    unsafe { (*regmap).SR & (USART_SR_RXNE | USART_SR_ORE) != 0 }
+  }
+
+  /// Wait for a byte, ignoring (but still counting) any reception errors
+  pub fn get_byte(&self) -> u8 {
+    self.receive().1
+  }
+
+  /// Wait for a byte, returning an error if the hardware flagged one
+  ///
+  /// The byte is dropped whenever an error is returned (on an overrun it's
+  /// actually intact, use get_byte if that matters). If more than one error
+  /// is flagged only the first one (in the order of the Error variants) is
+  /// reported.
+  pub fn read_byte(&self) -> Result<u8, Error> {
+    let (sr, byte) = self.receive();
+
+    if sr & USART_SR_ORE != 0 {
+      Err(Error::Overrun)
+    } else if sr & USART_SR_FE != 0 {
+      Err(Error::Framing)
+    } else if sr & USART_SR_NE != 0 {
+      Err(Error::Noise)
+    } else if sr & USART_SR_PE != 0 {
+      Err(Error::Parity)
+    } else {
+      Ok(byte)
+    }
+  }
+
+  pub fn stats(&self) -> Stats {
+    unsafe { port_stats[self.index()] }
+  }
+
+  pub fn clear_stats(&self) {
+    unsafe {
+      port_stats[self.index()] = EMPTY_STATS;
     }
   }
 
@@ -125,6 +588,9 @@ impl Usart {
       if i >= buf.len() { break; }
 
       if byte == 0x8 || byte == 0x7f {
+        // Nothing to erase at the beginning of the line
+        if i == 0 { continue; }
+
         self.send_byte(0x8);  // backspace
         self.send_byte(0x20); // space
         self.send_byte(0x8);  // backspace again
@@ -157,6 +623,33 @@ impl fmt::Write for Usart {
   }
 }
 
+/// A writer that drops whatever doesn't fit instead of waiting for the port
+pub struct NonBlocking(pub Usart);
+
+impl fmt::Write for NonBlocking {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    for byte in s.bytes() {
+      if !self.0.try_send_byte(byte) {
+        return Err(fmt::Error);
+      }
+    }
+
+    Ok(())
+  }
+}
+
+pub extern "C" fn usart1_irq_handler() {
+  USART1.handle_irq();
+}
+
+pub extern "C" fn usart2_irq_handler() {
+  USART2.handle_irq();
+}
+
+pub extern "C" fn usart3_irq_handler() {
+  USART3.handle_irq();
+}
+
 pub fn output_to(mut usart: Usart) {
   unsafe {
     current = Some(usart);
@@ -177,6 +670,22 @@ macro_rules! print {
   });
 }
 
+/// Like print! but never waits for the port - whatever doesn't fit into the
+/// transmit buffer is lost
+macro_rules! try_print {
+  ($($arg:tt)*) => ({
+    use core::fmt::Write;
+    unsafe {
+      match $crate::usart::current {
+        Some(usart) => {
+          let _ = $crate::usart::NonBlocking(usart).write_fmt(format_args!($($arg)*));
+        },
+        None => (),
+      }
+    }
+  });
+}
+
 /*
  * vi: ts=2 sw=2 expandtab
  */